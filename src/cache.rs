@@ -0,0 +1,560 @@
+use crate::{
+    config::{CacheConfig, ResolvedUpstream},
+    middlewares::auth::credentials_hash,
+    proxy::ProxyState,
+    utils::stable_hash,
+    UpstreamType,
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        HeaderMap, HeaderName, HeaderValue, StatusCode,
+    },
+    response::Response,
+};
+use http_body_util::BodyExt;
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// Header telling whether the response was served from the cache (`hit`) or not (`miss`)
+pub const CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// Interval in seconds between the sweeps of the expired responses spilled to disk
+const DISK_SWEEP_INTERVAL_SECS: u64 = 60;
+
+/// A response held in memory by the cache
+#[derive(Debug, Clone)]
+struct CachedResponse {
+    content_type: Option<HeaderValue>,
+    body: Bytes,
+}
+
+/// A response spilled to disk, along with its key to tell apart the keys with the same hash
+#[derive(Serialize, Deserialize)]
+struct SpilledResponse {
+    key: String,
+    content_type: Option<String>,
+    body: String,
+    expires_at: SystemTime,
+}
+
+/// A value held by the cache, along with when it was last used and when it expires
+#[derive(Debug)]
+pub struct Entry<V> {
+    used: u64,
+    pub expires_at: SystemTime,
+    pub value: V,
+}
+
+/// The cached values with a TTL, along with the order in which those were last used, so that the
+/// least recently used ones are evicted first
+#[derive(Debug)]
+pub struct Entries<V> {
+    values: HashMap<String, Entry<V>>,
+    /// The keys indexed by their last use, from the least to the most recently used
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl<V> Default for Entries<V> {
+    fn default() -> Self {
+        Self {
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+}
+
+impl<V: Clone> Entries<V> {
+    /// Returns the value if cached and not expired, marking it as the most recently used
+    pub fn get(&mut self, key: &str, now: SystemTime) -> Option<V> {
+        let entry = self.values.get_mut(key)?;
+        self.recency.remove(&entry.used);
+        if entry.expires_at <= now {
+            self.values.remove(key);
+            return None;
+        }
+
+        self.uses += 1;
+        entry.used = self.uses;
+        let value = entry.value.clone();
+        self.recency.insert(self.uses, key.to_string());
+        Some(value)
+    }
+
+    /// Caches the value as the most recently used, and returns the least recently used ones
+    /// evicted to not exceed the maximum number of values
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: V,
+        expires_at: SystemTime,
+        max_entries: usize,
+    ) -> Vec<(String, Entry<V>)> {
+        self.uses += 1;
+        let entry = Entry {
+            used: self.uses,
+            expires_at,
+            value,
+        };
+        if let Some(replaced) = self.values.insert(key.clone(), entry) {
+            self.recency.remove(&replaced.used);
+        }
+        self.recency.insert(self.uses, key);
+
+        let mut evicted = Vec::new();
+        while self.values.len() > max_entries {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.values.remove(&key) {
+                evicted.push((key, entry));
+            }
+        }
+        evicted
+    }
+}
+
+/// How the client asked for the cache to be used via the `Cache-Control` header, where `no-cache`
+/// skips the lookup (i.e. forces a fresh response, which is still cached) and `no-store` skips the
+/// cache altogether
+#[derive(Debug, Clone, Copy)]
+pub struct CacheControl {
+    pub lookup: bool,
+    pub store: bool,
+}
+
+impl From<&HeaderMap> for CacheControl {
+    fn from(headers: &HeaderMap) -> Self {
+        let directives = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase())
+            .collect::<Vec<_>>();
+        let no_store = directives.iter().any(|directive| directive == "no-store");
+        Self {
+            lookup: !no_store && !directives.iter().any(|directive| directive == "no-cache"),
+            store: !no_store,
+        }
+    }
+}
+
+/// The LRU cache of the responses to the deterministic requests, held in memory up to a maximum
+/// number of responses, with the least recently used ones spilled to disk if enabled
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCache {
+    entries: Arc<Mutex<Entries<CachedResponse>>>,
+}
+
+impl ResponseCache {
+    /// Returns the key of the request to the route, i.e. the request serialized with the fields
+    /// sorted (including the requested model), so that the same request is cached once regardless
+    /// of the order of its fields, along with the model the upstream serves (if rewritten), the
+    /// variant of the traffic split (if any), and the hash of the credentials forwarded to it
+    pub fn key(
+        route: &UpstreamType,
+        upstream: &ResolvedUpstream,
+        headers: &HeaderMap,
+        request: &impl Serialize,
+    ) -> String {
+        let request = serde_json::to_value(request)
+            .and_then(|request| serde_json::to_string(&request))
+            .unwrap_or_default();
+        let variant = upstream
+            .variant
+            .as_ref()
+            .map(|(split, variant)| format!("{split}/{variant}"))
+            .unwrap_or_default();
+        format!(
+            "{}:{}:{variant}:{:016x}:{request}",
+            route.as_str(),
+            upstream.model.as_deref().unwrap_or_default(),
+            credentials_hash(headers)
+        )
+    }
+
+    /// Returns the cached response to the request with the key, if any and not expired, looking
+    /// it up on disk (if enabled) on a miss in memory, in which case it's moved back to memory
+    pub async fn get(&self, config: &CacheConfig, key: &str) -> Option<Response> {
+        let now = SystemTime::now();
+        let cached = self.entries.lock().unwrap().get(key, now);
+        let response = match (cached, &config.disk_path) {
+            (Some(response), _) => response,
+            (None, Some(dir)) => {
+                let (path, key) = (spill_path(dir, key), key.to_string());
+                let (key, response, expires_at) =
+                    tokio::task::spawn_blocking(move || read_spilled(&path, key, now))
+                        .await
+                        .ok()??;
+                self.insert(config, key, response.clone(), expires_at);
+                response
+            }
+            (None, None) => return None,
+        };
+
+        let mut res = Response::new(Body::from(response.body));
+        if let Some(content_type) = response.content_type {
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+        }
+        res.headers_mut()
+            .insert(CACHE, HeaderValue::from_static("hit"));
+        Some(res)
+    }
+
+    /// Caches the response to the request with the key once fully sent to the client, as long as
+    /// it's a successful non-streaming response within the maximum size
+    pub fn store(&self, config: &CacheConfig, key: String, mut res: Response) -> Response {
+        res.headers_mut()
+            .insert(CACHE, HeaderValue::from_static("miss"));
+        let streaming = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+        if streaming || res.status() != StatusCode::OK {
+            return res;
+        }
+
+        let mut recorder = Recorder {
+            cache: self.clone(),
+            config: config.clone(),
+            key,
+            content_type: res.headers().get(CONTENT_TYPE).cloned(),
+            bytes: Vec::new(),
+            skipped: false,
+        };
+        res.map(|body| {
+            Body::new(body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref()
+                    && !recorder.skipped
+                {
+                    if recorder.bytes.len() + data.len() > recorder.config.max_entry_bytes {
+                        recorder.skipped = true;
+                        recorder.bytes = Vec::new();
+                    } else {
+                        recorder.bytes.extend_from_slice(data);
+                    }
+                }
+                frame
+            }))
+        })
+    }
+
+    /// Caches the response, spilling the evicted ones to disk if enabled
+    fn insert(
+        &self,
+        config: &CacheConfig,
+        key: String,
+        response: CachedResponse,
+        expires_at: SystemTime,
+    ) {
+        let evicted =
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(key, response, expires_at, config.max_entries);
+
+        let Some(dir) = &config.disk_path else {
+            return;
+        };
+        let now = SystemTime::now();
+        for (key, entry) in evicted {
+            if entry.expires_at <= now {
+                continue;
+            }
+            let path = spill_path(dir, &key);
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = write_spilled(&path, key, entry) {
+                    tracing::warn!("Failed to spill a cached response to disk: {e}");
+                }
+            });
+        }
+    }
+}
+
+/// The body of a response buffered as it's sent to the client, which is cached once dropped (i.e.
+/// once the response has been fully sent) unless too large or incomplete
+struct Recorder {
+    cache: ResponseCache,
+    config: CacheConfig,
+    key: String,
+    content_type: Option<HeaderValue>,
+    bytes: Vec<u8>,
+    skipped: bool,
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        // A body cut short (e.g. by the client disconnecting) fails to parse, so it's never cached
+        if self.skipped || serde_json::from_slice::<IgnoredAny>(&self.bytes).is_err() {
+            return;
+        }
+        let response = CachedResponse {
+            content_type: self.content_type.take(),
+            body: Bytes::from(std::mem::take(&mut self.bytes)),
+        };
+        let expires_at = SystemTime::now() + Duration::from_secs(self.config.ttl_secs);
+        self.cache.insert(
+            &self.config,
+            std::mem::take(&mut self.key),
+            response,
+            expires_at,
+        );
+    }
+}
+
+/// Returns the path of the file the response to the request with the key is spilled to
+fn spill_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{:016x}.json", stable_hash(key.as_bytes())))
+}
+
+/// Writes the response to disk, with the modification time of the file set to its expiration so
+/// that the expired responses can be swept without reading those
+fn write_spilled(path: &Path, key: String, entry: Entry<CachedResponse>) -> std::io::Result<()> {
+    let spilled = SpilledResponse {
+        key,
+        content_type: entry
+            .value
+            .content_type
+            .and_then(|value| value.to_str().ok().map(str::to_string)),
+        body: String::from_utf8_lossy(&entry.value.body).into_owned(),
+        expires_at: entry.expires_at,
+    };
+    let file = File::create(path)?;
+    serde_json::to_writer(&file, &spilled)?;
+    file.set_modified(entry.expires_at)
+}
+
+/// Reads the response to the request with the key from disk, removing the file if either read
+/// (as it's moved back to memory) or expired
+fn read_spilled(
+    path: &Path,
+    key: String,
+    now: SystemTime,
+) -> Option<(String, CachedResponse, SystemTime)> {
+    let spilled = serde_json::from_slice::<SpilledResponse>(&std::fs::read(path).ok()?).ok()?;
+    // Another key with the same hash, which is left as is
+    if spilled.key != key {
+        return None;
+    }
+    let _ = std::fs::remove_file(path);
+    if spilled.expires_at <= now {
+        return None;
+    }
+
+    let response = CachedResponse {
+        content_type: spilled
+            .content_type
+            .and_then(|value| HeaderValue::from_str(&value).ok()),
+        body: Bytes::from(spilled.body),
+    };
+    Some((key, response, spilled.expires_at))
+}
+
+/// Removes the expired responses spilled to disk periodically, re-reading the configuration
+/// before every sweep, so that the responses which are never requested again don't pile up
+pub async fn run_disk_sweeps(state: ProxyState) {
+    loop {
+        let config = state.config.load_full();
+        if let Some(dir) = config.cache.disk_path.clone()
+            && config.cache.enabled
+        {
+            let _ = tokio::task::spawn_blocking(move || sweep(&dir, SystemTime::now())).await;
+        }
+        tokio::time::sleep(Duration::from_secs(DISK_SWEEP_INTERVAL_SECS)).await;
+    }
+}
+
+fn sweep(dir: &Path, now: SystemTime) {
+    let Ok(files) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for file in files.flatten() {
+        let path = file.path();
+        let expired = file
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|expires_at| expires_at <= now);
+        if expired
+            && path
+                .extension()
+                .is_some_and(|extension| extension == "json")
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::to_bytes,
+        response::{IntoResponse, Json},
+    };
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_entries_lru() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+        let mut entries = Entries::<usize>::default();
+
+        assert!(entries.insert("a".into(), 0, later, 2).is_empty());
+        assert!(entries.insert("b".into(), 1, later, 2).is_empty());
+        // Using `a` makes `b` the least recently used one, so the one evicted
+        assert!(entries.get("a", now).is_some());
+        let evicted = entries.insert("c".into(), 2, later, 2);
+        assert_eq!(
+            evicted
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .collect::<Vec<_>>(),
+            ["b"]
+        );
+        assert!(entries.get("b", now).is_none());
+        assert_eq!(entries.get("c", now), Some(2));
+
+        // Expired responses are not returned, and are removed
+        assert!(entries.get("a", later).is_none());
+        assert_eq!(entries.values.len(), 1);
+        assert_eq!(entries.recency.len(), 1);
+    }
+
+    #[test]
+    fn test_key() {
+        let route = UpstreamType::ChatCompletions;
+        let upstream = ResolvedUpstream {
+            name: "vllm".to_string(),
+            uri: "http://localhost:8080".parse().unwrap(),
+            model: None,
+            timeouts: Default::default(),
+            fallback: None,
+            variant: None,
+            shadow: None,
+        };
+        let headers = HeaderMap::from_iter([(
+            HeaderName::from_static("api-key"),
+            HeaderValue::from_static("my-secret-key"),
+        )]);
+        let key = |upstream: &ResolvedUpstream, headers: &HeaderMap, request: Value| {
+            ResponseCache::key(&route, upstream, headers, &request)
+        };
+        let request = json!({"model": "phi", "seed": 1, "top_p": 0.5});
+        let cached = key(&upstream, &headers, request.clone());
+
+        // The same request is cached once regardless of the order of its fields
+        assert_eq!(
+            cached,
+            key(
+                &upstream,
+                &headers,
+                json!({"top_p": 0.5, "seed": 1, "model": "phi"})
+            )
+        );
+        assert!(!cached.contains("my-secret-key"));
+
+        // But not across models, credentials nor variants
+        assert_ne!(
+            cached,
+            key(
+                &upstream,
+                &headers,
+                json!({"model": "phi-mini", "seed": 1, "top_p": 0.5})
+            )
+        );
+        assert_ne!(cached, key(&upstream, &HeaderMap::new(), request.clone()));
+        let rewritten = ResolvedUpstream {
+            model: Some("microsoft/Phi-4".to_string()),
+            ..upstream.clone()
+        };
+        assert_ne!(cached, key(&rewritten, &headers, request.clone()));
+        let variant = ResolvedUpstream {
+            variant: Some(("phi-ab".to_string(), "phi-ft".to_string())),
+            ..upstream.clone()
+        };
+        assert_ne!(cached, key(&variant, &headers, request));
+    }
+
+    #[tokio::test]
+    async fn test_spill_to_disk() {
+        let dir =
+            std::env::temp_dir().join(format!("azure-openai-proxy-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = CacheConfig {
+            enabled: true,
+            max_entries: 1,
+            disk_path: Some(dir.clone()),
+            ..Default::default()
+        };
+        let cache = ResponseCache::default();
+        let store = async |key: &str, id: u64| {
+            let res = cache.store(
+                &config,
+                key.to_string(),
+                Json(json!({"id": id})).into_response(),
+            );
+            to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        };
+        // The responses are spilled in the background
+        let spilled = async |key: &str| {
+            let path = spill_path(&dir, key);
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !path.exists() {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .is_ok()
+        };
+
+        // The file names are stable, so that the spilled responses are found across restarts
+        assert_eq!(
+            spill_path(&dir, "a"),
+            dir.join(format!("{:016x}.json", 0xaf63_dc4c_8601_ec8c_u64))
+        );
+
+        // The least recently used response is spilled, and moved back to memory once requested
+        store("a", 1).await;
+        store("b", 2).await;
+        assert!(spilled("a").await);
+        let res = cache.get(&config, "a").await.unwrap();
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, json!({"id": 1}).to_string());
+        assert!(!spill_path(&dir, "a").exists());
+        assert!(spilled("b").await);
+
+        // Only the expired responses are swept
+        sweep(&dir, SystemTime::now());
+        assert!(spill_path(&dir, "b").exists());
+        sweep(
+            &dir,
+            SystemTime::now() + Duration::from_secs(config.ttl_secs),
+        );
+        assert!(!spill_path(&dir, "b").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let control = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_str(value).unwrap());
+            let control = CacheControl::from(&headers);
+            (control.lookup, control.store)
+        };
+        assert_eq!(control("max-age=0"), (true, true));
+        assert_eq!(control("No-Cache"), (false, true));
+        assert_eq!(control("no-cache, no-store"), (false, false));
+    }
+}
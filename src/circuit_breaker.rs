@@ -0,0 +1,345 @@
+use crate::{config::CircuitBreakerConfig, errors::AzureError, metrics::Metrics};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The state of the circuit breaker of an upstream
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// The requests are sent to the upstream, while tracking its failures
+    #[default]
+    Closed,
+
+    /// The requests are rejected right away, until the upstream is probed again
+    Open,
+
+    /// A limited number of trial requests are sent to the upstream to probe whether it recovered
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// Returns the value of the state reported on the metrics
+    fn as_metric(self) -> i64 {
+        match self {
+            Self::Closed => 0,
+            Self::HalfOpen => 1,
+            Self::Open => 2,
+        }
+    }
+}
+
+/// The circuit breaker of an upstream, with the failures within the current window
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    window_start: Instant,
+    window_requests: u32,
+    window_failures: u32,
+    opened_at: Instant,
+    trials: u32,
+    trial_successes: u32,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            window_start: now,
+            window_requests: 0,
+            window_failures: 0,
+            opened_at: now,
+            trials: 0,
+            trial_successes: 0,
+        }
+    }
+
+    fn transition(&mut self, upstream: &str, state: CircuitState, now: Instant) {
+        match state {
+            CircuitState::Open => tracing::warn!(
+                "Circuit breaker for upstream '{upstream}' is open after {} consecutive failures ({} out of {} requests failed)",
+                self.consecutive_failures,
+                self.window_failures,
+                self.window_requests
+            ),
+            CircuitState::HalfOpen => {
+                tracing::info!("Circuit breaker for upstream '{upstream}' is half-open")
+            }
+            CircuitState::Closed => {
+                tracing::info!("Circuit breaker for upstream '{upstream}' is closed")
+            }
+        }
+
+        *self = Self {
+            state,
+            opened_at: if state == CircuitState::Open {
+                now
+            } else {
+                self.opened_at
+            },
+            ..Self::new(now)
+        };
+    }
+}
+
+/// Per-upstream circuit breakers, which open after either `consecutive_failures` or once the
+/// failures reach the `error_rate` within the current window, rejecting the requests for
+/// `open_secs` before probing the upstream with up to `half_open_requests` trial requests
+#[derive(Debug, Clone, Default)]
+pub struct CircuitBreakers {
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+impl CircuitBreakers {
+    /// Checks whether the request can be sent to the upstream, returning the permit to record its
+    /// outcome with, or rejecting it with a 503 status while the circuit is open
+    pub fn allow(
+        &self,
+        upstream: &str,
+        config: &CircuitBreakerConfig,
+        metrics: &Metrics,
+    ) -> Result<CircuitPermit, AzureError> {
+        self.allow_at(upstream, config, metrics, Instant::now())
+    }
+
+    fn allow_at(
+        &self,
+        upstream: &str,
+        config: &CircuitBreakerConfig,
+        metrics: &Metrics,
+        now: Instant,
+    ) -> Result<CircuitPermit, AzureError> {
+        let mut permit = CircuitPermit {
+            breakers: self.clone(),
+            upstream: upstream.to_string(),
+            config: config.clone(),
+            metrics: metrics.clone(),
+            trial: false,
+        };
+        if !config.enabled {
+            return Ok(permit);
+        }
+
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(upstream.to_string())
+            .or_insert_with(|| Circuit::new(now));
+
+        if circuit.state == CircuitState::Open {
+            let open_for = Duration::from_secs(config.open_secs);
+            let elapsed = now.saturating_duration_since(circuit.opened_at);
+            if elapsed < open_for {
+                metrics.record_upstream_rejected(upstream, "circuit_open");
+                return Err(AzureError::ServiceUnavailable(
+                    format!("The upstream '{upstream}' is failing, rejecting requests for now."),
+                    (open_for - elapsed).as_secs_f64().ceil().max(1.0) as u64,
+                ));
+            }
+            circuit.transition(upstream, CircuitState::HalfOpen, now);
+            metrics.record_circuit_state(upstream, CircuitState::HalfOpen.as_metric());
+        }
+
+        if circuit.state == CircuitState::HalfOpen {
+            if circuit.trials >= config.half_open_requests {
+                metrics.record_upstream_rejected(upstream, "circuit_open");
+                return Err(AzureError::ServiceUnavailable(
+                    format!("The upstream '{upstream}' is recovering, rejecting requests for now."),
+                    1,
+                ));
+            }
+            circuit.trials += 1;
+            permit.trial = true;
+        }
+
+        Ok(permit)
+    }
+
+    /// Returns the state of the circuit breaker of the upstream
+    pub fn state(&self, upstream: &str) -> CircuitState {
+        self.circuits
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .map(|circuit| circuit.state)
+            .unwrap_or_default()
+    }
+
+    /// Returns the state of the circuit breaker of each upstream that has received requests
+    pub fn snapshot(&self) -> BTreeMap<String, CircuitState> {
+        self.circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, circuit)| (name.clone(), circuit.state))
+            .collect()
+    }
+
+    fn record(&self, permit: &CircuitPermit, success: bool, now: Instant) {
+        let config = &permit.config;
+        let upstream = permit.upstream.as_str();
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(upstream) else {
+            return;
+        };
+
+        let state = circuit.state;
+        match (state, permit.trial) {
+            (CircuitState::Closed, _) => {
+                if now.saturating_duration_since(circuit.window_start)
+                    >= Duration::from_secs(config.window_secs)
+                {
+                    circuit.window_start = now;
+                    circuit.window_requests = 0;
+                    circuit.window_failures = 0;
+                }
+                circuit.window_requests += 1;
+                if success {
+                    circuit.consecutive_failures = 0;
+                } else {
+                    circuit.consecutive_failures += 1;
+                    circuit.window_failures += 1;
+                }
+
+                let error_rate = circuit.window_failures as f64 / circuit.window_requests as f64;
+                if circuit.consecutive_failures >= config.consecutive_failures
+                    || (circuit.window_requests >= config.min_requests
+                        && error_rate >= config.error_rate)
+                {
+                    circuit.transition(upstream, CircuitState::Open, now);
+                }
+            }
+            (CircuitState::HalfOpen, true) => {
+                circuit.trials = circuit.trials.saturating_sub(1);
+                if !success {
+                    circuit.transition(upstream, CircuitState::Open, now);
+                } else {
+                    circuit.trial_successes += 1;
+                    if circuit.trial_successes >= config.half_open_requests {
+                        circuit.transition(upstream, CircuitState::Closed, now);
+                    }
+                }
+            }
+            // The outcome of the requests sent before the circuit changed state is ignored
+            _ => {}
+        }
+
+        if circuit.state != state {
+            permit
+                .metrics
+                .record_circuit_state(upstream, circuit.state.as_metric());
+        }
+    }
+
+    /// Releases the trial of a request dropped before its outcome was recorded
+    fn release(&self, permit: &CircuitPermit) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(circuit) = circuits.get_mut(&permit.upstream)
+            && circuit.state == CircuitState::HalfOpen
+        {
+            circuit.trials = circuit.trials.saturating_sub(1);
+        }
+    }
+}
+
+/// A request allowed by the circuit breaker of an upstream, whose outcome is to be recorded once
+/// the upstream responds
+#[derive(Debug)]
+pub struct CircuitPermit {
+    breakers: CircuitBreakers,
+    upstream: String,
+    config: CircuitBreakerConfig,
+    metrics: Metrics,
+    trial: bool,
+}
+
+impl CircuitPermit {
+    /// Records whether the request succeeded, where only the connection failures and the 5XX
+    /// responses are failures, as the rest are caused by the request itself
+    pub fn record(mut self, success: bool) {
+        if self.config.enabled {
+            self.breakers.record(&self, success, Instant::now());
+        }
+        self.trial = false;
+    }
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        if self.trial {
+            self.breakers.release(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_and_recover() {
+        let breakers = CircuitBreakers::default();
+        let metrics = Metrics::default();
+        let config = CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 2,
+            open_secs: 10,
+            half_open_requests: 1,
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            let permit = breakers.allow_at("vllm", &config, &metrics, now).unwrap();
+            breakers.record(&permit, false, now);
+        }
+        assert_eq!(breakers.snapshot()["vllm"], CircuitState::Open);
+        assert!(matches!(
+            breakers.allow_at("vllm", &config, &metrics, now + Duration::from_secs(5)),
+            Err(AzureError::ServiceUnavailable(_, 5))
+        ));
+
+        // Once open for long enough, a single trial request is let through
+        let later = now + Duration::from_secs(10);
+        let trial = breakers.allow_at("vllm", &config, &metrics, later).unwrap();
+        assert_eq!(breakers.snapshot()["vllm"], CircuitState::HalfOpen);
+        assert!(breakers.allow_at("vllm", &config, &metrics, later).is_err());
+
+        trial.record(true);
+        assert_eq!(breakers.snapshot()["vllm"], CircuitState::Closed);
+        assert!(breakers.allow_at("vllm", &config, &metrics, later).is_ok());
+
+        let encoded = metrics.encode();
+        for line in [
+            r#"azure_openai_proxy_upstream_circuit_state{upstream="vllm"} 0"#,
+            r#"azure_openai_proxy_upstream_rejected_requests_total{reason="circuit_open",upstream="vllm"} 2"#,
+        ] {
+            assert!(encoded.contains(line), "missing `{line}` in:\n{encoded}");
+        }
+    }
+
+    #[test]
+    fn test_open_on_error_rate() {
+        let breakers = CircuitBreakers::default();
+        let metrics = Metrics::default();
+        let config = CircuitBreakerConfig {
+            enabled: true,
+            consecutive_failures: 100,
+            error_rate: 0.5,
+            min_requests: 4,
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        // Alternating failures never reach the consecutive failures, but reach the error rate
+        for success in [true, false, true, false] {
+            let permit = breakers.allow_at("vllm", &config, &metrics, now).unwrap();
+            breakers.record(&permit, success, now);
+        }
+        assert_eq!(breakers.snapshot()["vllm"], CircuitState::Open);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{config::UpstreamConfig, errors::AzureError, metrics::Metrics};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Seconds the client is asked to wait before retrying, when rejected while waiting for the
+/// upstream
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// The concurrency limit of an upstream, with the number of requests waiting for a permit
+#[derive(Debug)]
+struct UpstreamLimit {
+    semaphore: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    queued: AtomicUsize,
+}
+
+/// Per-upstream concurrency limiter, which admits up to `max_concurrent_requests` in flight to each
+/// upstream and queues the rest, up to `max_queued_requests` for at most `max_queue_wait_secs`
+#[derive(Debug, Clone, Default)]
+pub struct ConcurrencyLimiter {
+    upstreams: Arc<Mutex<HashMap<String, Arc<UpstreamLimit>>>>,
+}
+
+impl ConcurrencyLimiter {
+    /// Returns the limit of the upstream, replacing it if the limit changed on reload, in which
+    /// case the requests in flight keep the permits of the previous one until completed
+    fn limit(&self, upstream: &str, max_concurrent_requests: usize) -> Arc<UpstreamLimit> {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        match upstreams.get(upstream) {
+            Some(limit) if limit.max_concurrent_requests == max_concurrent_requests => {
+                limit.clone()
+            }
+            _ => {
+                let limit = Arc::new(UpstreamLimit {
+                    semaphore: Arc::new(Semaphore::new(max_concurrent_requests)),
+                    max_concurrent_requests,
+                    queued: AtomicUsize::new(0),
+                });
+                upstreams.insert(upstream.to_string(), limit.clone());
+                limit
+            }
+        }
+    }
+
+    /// Waits until the request can be sent to the upstream, returning the permit to be held until
+    /// the response has been fully sent (if the upstream is limited), or rejecting the request if
+    /// either the queue is full (429) or the wait exceeds the maximum queue wait (503)
+    pub async fn acquire(
+        &self,
+        upstream: &str,
+        config: &UpstreamConfig,
+        metrics: &Metrics,
+    ) -> Result<Option<UpstreamPermit>, AzureError> {
+        let Some(max_concurrent_requests) = config.max_concurrent_requests else {
+            return Ok(None);
+        };
+        let limit = self.limit(upstream, max_concurrent_requests);
+
+        let permit = match limit.semaphore.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if limit.queued.fetch_add(1, Ordering::SeqCst) >= config.max_queued_requests {
+                    limit.queued.fetch_sub(1, Ordering::SeqCst);
+                    metrics.record_upstream_rejected(upstream, "queue_full");
+                    return Err(AzureError::TooManyRequests(
+                        format!("Too many requests waiting for the upstream '{upstream}'."),
+                        RETRY_AFTER_SECS,
+                    ));
+                }
+
+                // Leaves the queue even if the request is dropped while waiting
+                let _slot = QueueSlot {
+                    limit: &limit,
+                    metrics,
+                    upstream,
+                };
+                metrics.record_upstream_admission(upstream, 0, 1);
+
+                let wait = Duration::from_secs(config.max_queue_wait_secs);
+                match tokio::time::timeout(wait, limit.semaphore.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => permit,
+                    // The semaphore is never closed, but handled as a timeout regardless
+                    Ok(Err(_)) | Err(_) => {
+                        metrics.record_upstream_rejected(upstream, "queue_timeout");
+                        return Err(AzureError::ServiceUnavailable(
+                            format!(
+                                "Timed out after {}s waiting for the upstream '{upstream}'.",
+                                config.max_queue_wait_secs
+                            ),
+                            RETRY_AFTER_SECS,
+                        ));
+                    }
+                }
+            }
+        };
+
+        metrics.record_upstream_admission(upstream, 1, 0);
+        Ok(Some(UpstreamPermit {
+            _permit: permit,
+            metrics: metrics.clone(),
+            upstream: upstream.to_string(),
+        }))
+    }
+}
+
+/// A request waiting in the queue of an upstream, removed from the queue once dropped
+struct QueueSlot<'a> {
+    limit: &'a UpstreamLimit,
+    metrics: &'a Metrics,
+    upstream: &'a str,
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.limit.queued.fetch_sub(1, Ordering::SeqCst);
+        self.metrics.record_upstream_admission(self.upstream, 0, -1);
+    }
+}
+
+/// A request in flight to an upstream, which releases its slot once dropped
+#[derive(Debug)]
+pub struct UpstreamPermit {
+    _permit: OwnedSemaphorePermit,
+    metrics: Metrics,
+    upstream: String,
+}
+
+impl Drop for UpstreamPermit {
+    fn drop(&mut self) {
+        self.metrics
+            .record_upstream_admission(&self.upstream, -1, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_with_queue() {
+        let limiter = ConcurrencyLimiter::default();
+        let metrics = Metrics::default();
+        let config = UpstreamConfig {
+            max_concurrent_requests: Some(1),
+            max_queued_requests: 1,
+            max_queue_wait_secs: 5,
+            ..Default::default()
+        };
+
+        let permit = limiter
+            .acquire("vllm", &config, &metrics)
+            .await
+            .unwrap()
+            .expect("the upstream should be limited");
+
+        // The second request waits in the queue, so the third one is rejected right away
+        let queued = tokio::spawn({
+            let (limiter, config, metrics) = (limiter.clone(), config.clone(), metrics.clone());
+            async move { limiter.acquire("vllm", &config, &metrics).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(matches!(
+            limiter.acquire("vllm", &config, &metrics).await,
+            Err(AzureError::TooManyRequests(..))
+        ));
+
+        // Once the first request completes, the queued one is admitted
+        drop(permit);
+        assert!(matches!(queued.await.unwrap(), Ok(Some(_))));
+
+        // Without releasing the permit, the queued request times out after the maximum wait
+        let _permit = limiter.acquire("vllm", &config, &metrics).await.unwrap();
+        assert!(matches!(
+            limiter.acquire("vllm", &config, &metrics).await,
+            Err(AzureError::ServiceUnavailable(..))
+        ));
+
+        let encoded = metrics.encode();
+        for line in [
+            r#"azure_openai_proxy_upstream_in_flight_requests{upstream="vllm"} 1"#,
+            r#"azure_openai_proxy_upstream_queued_requests{upstream="vllm"} 0"#,
+            r#"azure_openai_proxy_upstream_rejected_requests_total{reason="queue_full",upstream="vllm"} 1"#,
+            r#"azure_openai_proxy_upstream_rejected_requests_total{reason="queue_timeout",upstream="vllm"} 1"#,
+        ] {
+            assert!(encoded.contains(line), "missing `{line}` in:\n{encoded}");
+        }
+    }
+}
@@ -0,0 +1,1407 @@
+use crate::{errors::ConfigError, utils::build_upstream_uri, Cli, UpstreamType};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+};
+
+/// Name of the upstream that the `--upstream-host` and `--upstream-port` CLI arguments (or their
+/// environment variables) refer to, and the one the `--upstream-type` route is served by
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+/// Placeholder the secrets are replaced with when printing the configuration
+const REDACTED: &str = "[REDACTED]";
+
+/// Configuration of the proxy, that can be provided via a TOML or YAML file, and then layered with
+/// both the environment variables and the CLI arguments on top, which take precedence over the
+/// values defined within the file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the proxy listens on
+    pub server: ServerConfig,
+
+    /// The OpenAI-compatible APIs the requests are proxied to, indexed by name
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+
+    /// The pools of upstreams serving the same model (i.e. replicas) the requests are balanced
+    /// across, indexed by name, which can be used as the upstream of either a route or a model
+    pub pools: BTreeMap<String, PoolConfig>,
+
+    /// The traffic splits across variants (e.g. fine-tunes) served by different upstreams or
+    /// models, indexed by name, which can be used as the upstream of either a route or a model
+    pub splits: BTreeMap<String, SplitConfig>,
+
+    /// The upstream serving each of the Azure AI Model Inference API routes (i.e.
+    /// `chat-completions` and `embeddings`), only the routes defined here are exposed
+    pub routes: BTreeMap<UpstreamType, RouteConfig>,
+
+    /// The models (or model aliases) that are served by an upstream other than the default one
+    /// for the route, indexed by the `model` value provided within the request payload
+    pub models: BTreeMap<String, ModelConfig>,
+
+    /// The API keys accepted by the proxy
+    pub auth: AuthConfig,
+
+    /// The limits applied to the incoming requests
+    pub limits: LimitsConfig,
+
+    /// The rate limits applied to the requests to `/chat/completions` and `/embeddings`
+    pub rate_limit: RateLimitConfig,
+
+    /// The retries of the failed requests to the upstreams
+    pub retry: RetryConfig,
+
+    /// The circuit breakers of the upstreams
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// The logging configuration of the proxy
+    pub logging: LoggingConfig,
+
+    /// The OpenTelemetry tracing configuration of the proxy
+    pub tracing: TracingConfig,
+
+    /// The active health checks run periodically against each upstream
+    pub health_check: HealthCheckConfig,
+
+    /// The Prometheus metrics exposed by the proxy
+    pub metrics: MetricsConfig,
+
+    /// The cache of the responses to the deterministic chat completions requests
+    pub cache: CacheConfig,
+
+    /// The cache of the embeddings of each input within the embeddings requests
+    pub embeddings_cache: EmbeddingsCacheConfig,
+
+    /// The batching of the concurrent embeddings requests into a single upstream request
+    pub embeddings_batching: EmbeddingsBatchingConfig,
+
+    /// The splitting of the oversized embeddings requests into smaller upstream requests
+    pub embeddings_splitting: EmbeddingsSplittingConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig::default(),
+            upstreams: BTreeMap::from([(DEFAULT_UPSTREAM.to_string(), UpstreamConfig::default())]),
+            pools: BTreeMap::new(),
+            splits: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            models: BTreeMap::new(),
+            auth: AuthConfig::default(),
+            limits: LimitsConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            retry: RetryConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            logging: LoggingConfig::default(),
+            tracing: TracingConfig::default(),
+            health_check: HealthCheckConfig::default(),
+            metrics: MetricsConfig::default(),
+            cache: CacheConfig::default(),
+            embeddings_cache: EmbeddingsCacheConfig::default(),
+            embeddings_batching: EmbeddingsBatchingConfig::default(),
+            embeddings_splitting: EmbeddingsSplittingConfig::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The host the proxy listens on, either an IP address or an Unix domain socket path with the
+    /// `unix://` scheme
+    pub host: String,
+
+    /// The port the proxy listens on, ignored when listening on an Unix domain socket
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: 80,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// The host of the upstream, either an IP address or hostname (optionally with the scheme), or
+    /// an Unix domain socket path with the `unix://` scheme
+    pub host: String,
+
+    /// The port of the upstream, ignored when connecting through an Unix domain socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// The maximum number of requests in flight to the upstream, with the rest waiting in a queue
+    /// until one of those completes; if not set, the requests are not limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+
+    /// The maximum number of requests waiting in the queue, with the rest rejected right away
+    /// with a 429 status
+    pub max_queued_requests: usize,
+
+    /// The maximum time in seconds a request waits in the queue, before being rejected with a 503
+    /// status
+    pub max_queue_wait_secs: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            host: "0.0.0.0".to_string(),
+            port: Some(8080),
+            max_concurrent_requests: None,
+            max_queued_requests: 100,
+            max_queue_wait_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// The names of the upstreams within the pool
+    pub upstreams: Vec<String>,
+
+    /// How the requests are balanced across the upstreams, either `round-robin`,
+    /// `least-in-flight`, `power-of-two-choices`, `prefix-hash` or `least-loaded`
+    pub strategy: LoadBalancingStrategy,
+
+    /// The time in seconds over which the traffic sent to an upstream that becomes healthy again
+    /// is gradually increased, so that it's not overwhelmed right away (e.g. with cold caches)
+    pub slow_start_secs: u64,
+
+    /// The number of characters of the chat messages (besides the system messages) hashed with
+    /// the `prefix-hash` strategy
+    pub prefix_chars: usize,
+
+    /// The maximum requests in flight to an upstream with the `prefix-hash` strategy, relative to
+    /// the average across the upstreams, over which the requests spill over to the next upstream
+    pub max_load_factor: f64,
+
+    /// The path of the Prometheus metrics of the inference engine, scraped with the
+    /// `least-loaded` strategy
+    pub metrics_path: String,
+
+    /// The interval in seconds between the scrapes of the engine metrics, which are considered
+    /// stale after three intervals
+    pub scrape_interval_secs: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            strategy: LoadBalancingStrategy::default(),
+            slow_start_secs: 0,
+            prefix_chars: 1024,
+            max_load_factor: 1.25,
+            metrics_path: "/metrics".to_string(),
+            scrape_interval_secs: 5,
+        }
+    }
+}
+
+/// How the requests are balanced across the upstreams of a pool
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadBalancingStrategy {
+    /// Each upstream in turn
+    #[default]
+    RoundRobin,
+
+    /// The upstream with the fewest requests in flight
+    LeastInFlight,
+
+    /// The upstream with the fewest requests in flight out of two picked at random
+    PowerOfTwoChoices,
+
+    /// The same upstream for the chat requests sharing a prefix, so that the upstream can reuse
+    /// its KV cache for the prefix, unless that upstream is overloaded
+    PrefixHash,
+
+    /// The upstream with the fewest running and waiting requests and the lowest KV cache usage,
+    /// as reported by the metrics of the inference engine
+    LeastLoaded,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SplitConfig {
+    /// The variants the requests are split across, indexed by name
+    pub variants: BTreeMap<String, VariantConfig>,
+
+    /// What the requests stick to the same variant by, either `api-key` or `header`, where the
+    /// requests without it are split at random; if not set, all the requests are split at random
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickyKey>,
+
+    /// The header the requests stick to the same variant by, with `sticky = "header"` e.g. a user
+    /// ID header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sticky_header: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VariantConfig {
+    /// The name of the upstream (or pool) serving the variant
+    pub upstream: String,
+
+    /// The model name to forward to the upstream instead of the one in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// The share of the requests sent to the variant, relative to the weights of the rest
+    pub weight: u32,
+}
+
+/// What the requests of a traffic split stick to the same variant by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StickyKey {
+    /// The API key of the request
+    ApiKey,
+
+    /// The value of the `sticky_header` of the request
+    Header,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    /// The name of the upstream (or pool) serving the route by default
+    pub upstream: String,
+
+    /// The timeouts of the requests to the upstream
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+
+    /// The upstream (and model) the requests are sent to when the upstream fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<FallbackConfig>,
+
+    /// The upstream a share of the requests are mirrored to, whose responses are discarded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<ShadowConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// The name of the upstream (or pool) serving the model
+    pub upstream: String,
+
+    /// The model name to forward to the upstream instead of the one in the request, useful when
+    /// the key is an alias for the model served by the upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// The timeouts of the requests to the upstream, overriding the ones of the route
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+
+    /// The upstream (and model) the requests are sent to when the upstream fails, as the fallback
+    /// of the route may not serve the model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<FallbackConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// The name of the upstream (or pool) the requests are mirrored to e.g. running a candidate
+    /// version of the inference engine
+    pub upstream: String,
+
+    /// The model name to forward to the shadow upstream instead of the one sent to the upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// The percentage of the requests mirrored to the shadow upstream, between 0 and 100
+    pub percent: f64,
+
+    /// Whether to log the differences between the responses of the upstream and the shadow one,
+    /// for the non-streaming responses
+    #[serde(default)]
+    pub diff: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// The name of the upstream (or pool) the requests fail over to
+    pub upstream: String,
+
+    /// The model name to forward to the fallback upstream instead of the one in the request, e.g.
+    /// a smaller model served on CPU
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// The timeout in seconds to connect to the upstream, defaults to 10s if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_secs: Option<u64>,
+
+    /// The timeout in seconds of the whole request, including its retries and the response body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_secs: Option<u64>,
+
+    /// The timeout in seconds until the upstream responds (i.e. until the response headers), which
+    /// for non-streaming requests includes the whole generation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_byte_secs: Option<u64>,
+
+    /// The timeout in seconds between two chunks of the response body e.g. two streamed events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+}
+
+impl TimeoutsConfig {
+    /// Returns the timeouts with the ones not set taken from the given fallback
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            connect_secs: self.connect_secs.or(fallback.connect_secs),
+            request_secs: self.request_secs.or(fallback.request_secs),
+            first_byte_secs: self.first_byte_secs.or(fallback.first_byte_secs),
+            idle_secs: self.idle_secs.or(fallback.idle_secs),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The API keys accepted via either the `api-key` or the `Authorization: Bearer` headers; if
+    /// empty, the authentication is disabled
+    pub api_keys: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// The maximum size in bytes of the request body, defaults to 2MB if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_request_body_bytes: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// What the rate limits are applied to, either each `api-key`, each `model` or each
+    /// `client-ip`
+    pub key: RateLimitKey,
+
+    /// The maximum number of requests per minute; if not set, the requests are not limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requests_per_minute: Option<u64>,
+
+    /// The maximum number of tokens per minute, estimated from both the size of the request and
+    /// `max_tokens` and then corrected with the `usage` reported by the upstream; if not set, the
+    /// tokens are not limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tokens_per_minute: Option<u64>,
+
+    /// Whether the client IP is read from the `x-forwarded-for` header (if any) rather than from
+    /// the connection, only to be enabled when the proxy is behind a trusted load balancer
+    pub trust_forwarded_for: bool,
+}
+
+/// What the rate limits are applied to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RateLimitKey {
+    /// Each API key, or each client IP for the requests without an authenticated API key (e.g. if
+    /// the authentication is disabled)
+    #[default]
+    ApiKey,
+
+    /// Each requested model
+    Model,
+
+    /// Each client IP
+    ClientIp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// The maximum number of retries of each request, where `0` (the default) disables the retries
+    pub max_retries: u32,
+
+    /// The upstream response statuses the requests are retried on, besides the connection
+    /// failures, as those are returned before the upstream starts processing the request
+    pub statuses: Vec<u16>,
+
+    /// The initial backoff in milliseconds before the first retry, doubled on every retry
+    pub initial_backoff_ms: u64,
+
+    /// The maximum backoff in milliseconds before each retry
+    pub max_backoff_ms: u64,
+
+    /// The maximum time in seconds spent on a request including its retries, after which the last
+    /// failure is returned without retrying
+    pub max_retry_secs: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 0,
+            statuses: vec![502, 503],
+            initial_backoff_ms: 100,
+            max_backoff_ms: 2000,
+            max_retry_secs: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Whether the requests to a failing upstream are rejected right away or not
+    pub enabled: bool,
+
+    /// The number of consecutive failed requests (i.e. connection failures or 5XX responses) to
+    /// open the circuit
+    pub consecutive_failures: u32,
+
+    /// The ratio of failed requests within the window to open the circuit, between 0 and 1
+    pub error_rate: f64,
+
+    /// The minimum number of requests within the window for the error rate to be considered
+    pub min_requests: u32,
+
+    /// The duration in seconds of the window the error rate is computed over
+    pub window_secs: u64,
+
+    /// The time in seconds the circuit stays open before probing the upstream again
+    pub open_secs: u64,
+
+    /// The number of trial requests sent to the upstream while half-open, all of which need to
+    /// succeed for the circuit to close again
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window_secs: 30,
+            open_secs: 30,
+            half_open_requests: 1,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// The log level of the proxy i.e. `trace`, `debug`, `info`, `warn` or `error`, which is
+    /// overridden by the `RUST_LOG` environment variable if set
+    pub level: String,
+
+    /// The format of the logs, either `text` or `json`
+    pub format: LogFormat,
+
+    /// Whether the request payloads are logged at `debug` level, as those contain the user
+    /// prompts, which are not logged by default
+    pub log_payloads: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::default(),
+            log_payloads: false,
+        }
+    }
+}
+
+/// The format of the logs written to the standard output
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable logs
+    #[default]
+    Text,
+
+    /// One JSON object per line, with the fields of both the event and the current span, to be
+    /// ingested by a log collector e.g. Azure Monitor
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    /// The base URL of the OpenTelemetry collector (or agent) the spans are exported to over
+    /// plain OTLP/HTTP e.g. `http://localhost:4318`; if not set, the spans are not exported
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub otlp_endpoint: Option<String>,
+
+    /// The `service.name` of the exported spans
+    pub service_name: String,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: env!("CARGO_PKG_NAME").to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// Whether the upstreams are periodically health checked or not
+    pub enabled: bool,
+
+    /// The path of the upstream endpoint to check, expected to respond with a 2XX status when
+    /// healthy e.g. `/health` for vLLM, SGLang, TGI or TEI, or `/v1/models` for any other
+    /// OpenAI-compatible API
+    pub path: String,
+
+    /// The interval in seconds between health checks
+    pub interval_secs: u64,
+
+    /// The timeout in seconds for each health check
+    pub timeout_secs: u64,
+
+    /// The number of consecutive failed health checks for an upstream to be marked as unhealthy
+    pub unhealthy_threshold: u32,
+
+    /// The number of consecutive successful health checks for an upstream to be marked as healthy
+    pub healthy_threshold: u32,
+
+    /// If set, the proxy exits when a route can no longer be served as its upstream (or every
+    /// upstream of its pool) stays unhealthy for longer than the given seconds, so that the
+    /// container is restarted; only once the upstream has been healthy at least once, so that the
+    /// startup of the upstream (e.g. loading the model weights) is not considered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_after_unhealthy_secs: Option<u64>,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: "/health".to_string(),
+            interval_secs: 10,
+            timeout_secs: 5,
+            unhealthy_threshold: 3,
+            healthy_threshold: 1,
+            exit_after_unhealthy_secs: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// The port of the separate listener serving both `/metrics` and `/admin/reload`, on the same
+    /// host as the proxy (or `0.0.0.0` when the proxy listens on an Unix domain socket); if not
+    /// set, `/metrics` is served by the proxy itself, and `/admin/reload` is not served at all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Whether the responses to the non-streaming chat completions requests with either a
+    /// `temperature` of 0 or a `seed` are cached, and served to the identical requests from there
+    pub enabled: bool,
+
+    /// The maximum number of responses kept in memory, after which the least recently used ones
+    /// are evicted (or spilled to disk, if `disk_path` is set)
+    pub max_entries: usize,
+
+    /// The time in seconds a response is served from the cache for
+    pub ttl_secs: u64,
+
+    /// The maximum size in bytes of the responses to cache, so that larger ones are not cached
+    pub max_entry_bytes: usize,
+
+    /// The existing directory the responses evicted from memory are spilled to, and read back
+    /// from on a miss in memory; if not set, the evicted responses are discarded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disk_path: Option<PathBuf>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: 1000,
+            ttl_secs: 3600,
+            max_entry_bytes: 1024 * 1024,
+            disk_path: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingsCacheConfig {
+    /// Whether the embeddings of each input are cached, so that only the inputs not cached yet
+    /// are sent to the upstream
+    pub enabled: bool,
+
+    /// The maximum number of embeddings kept in memory, after which the least recently used ones
+    /// are evicted, where each embedding takes 4 bytes per dimension
+    pub max_entries: usize,
+
+    /// The time in seconds an embedding is served from the cache for
+    pub ttl_secs: u64,
+}
+
+impl Default for EmbeddingsCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: 100_000,
+            ttl_secs: 86400,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingsBatchingConfig {
+    /// Whether the concurrent embeddings requests with the same parameters (e.g. the same model)
+    /// are collected and sent to the upstream as a single batch
+    pub enabled: bool,
+
+    /// The time in milliseconds a batch waits for more requests since the first one, before being
+    /// sent to the upstream
+    pub window_ms: u64,
+
+    /// The maximum number of inputs within a batch, which is sent right away once reached
+    pub max_batch_size: usize,
+
+    /// The maximum number of tokens within a batch, estimated from the size of the inputs; if not
+    /// set, the batches are only limited by the number of inputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_batch_tokens: Option<u64>,
+}
+
+impl Default for EmbeddingsBatchingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_ms: 5,
+            max_batch_size: 32,
+            max_batch_tokens: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmbeddingsSplittingConfig {
+    /// Whether the embeddings requests exceeding the limits are split into sub-batches within
+    /// those, e.g. as TEI rejects the batches larger than its `max_client_batch_size`
+    pub enabled: bool,
+
+    /// The maximum number of inputs within each sub-batch
+    pub max_batch_size: usize,
+
+    /// The maximum number of tokens within each sub-batch, estimated from the size of the inputs;
+    /// if not set, the sub-batches are only limited by the number of inputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_batch_tokens: Option<u64>,
+
+    /// The maximum number of sub-batches of a request in flight to the upstream at once
+    pub max_concurrency: usize,
+}
+
+impl Default for EmbeddingsSplittingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_batch_size: 32,
+            max_batch_tokens: None,
+            max_concurrency: 4,
+        }
+    }
+}
+
+/// The upstream a request is proxied to, after resolving the route and the model
+#[derive(Debug, Clone)]
+pub struct ResolvedUpstream {
+    /// The name of the upstream
+    pub name: String,
+
+    /// The URI of the upstream, without the path
+    pub uri: axum::http::Uri,
+
+    /// The model name to forward to the upstream, if it has to be rewritten
+    pub model: Option<String>,
+
+    /// The timeouts of the requests to the upstream, from the model or the route otherwise
+    pub timeouts: TimeoutsConfig,
+
+    /// The fallback of the model or the route, if the upstream is not a fallback already
+    pub fallback: Option<FallbackConfig>,
+
+    /// The traffic split and the variant the request was assigned to, if split
+    pub variant: Option<(String, String)>,
+
+    /// The shadow upstream of the route, if the upstream is not a fallback
+    pub shadow: Option<ShadowConfig>,
+}
+
+impl Config {
+    /// Loads the configuration from the file (if any), and then applies the overrides from the
+    /// CLI arguments and environment variables on top, to finally validate the merged
+    /// configuration
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Serializes the configuration as TOML, with the secrets (i.e. the API keys) redacted so that
+    /// it can be safely printed e.g. within the CI logs
+    pub fn to_redacted_toml(&self) -> Result<String, ConfigError> {
+        let mut config = self.clone();
+        for api_key in &mut config.auth.api_keys {
+            *api_key = REDACTED.to_string();
+        }
+        toml::to_string_pretty(&config).map_err(|e| ConfigError::Serialize(e.to_string()))
+    }
+
+    /// Reads the configuration file, parsed as YAML if the extension is either `.yaml` or `.yml`,
+    /// and as TOML otherwise
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Read(path.display().to_string(), e.to_string()))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.display().to_string(), e.to_string())),
+            _ => toml::from_str(&content)
+                .map_err(|e| ConfigError::Parse(path.display().to_string(), e.to_string())),
+        }
+    }
+
+    /// Overrides the configuration values with the ones provided via either the CLI arguments or
+    /// the environment variables, as those take precedence over the configuration file
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(host) = &cli.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+
+        if cli.upstream_host.is_some() || cli.upstream_port.is_some() {
+            let upstream = self
+                .upstreams
+                .entry(DEFAULT_UPSTREAM.to_string())
+                .or_default();
+            if let Some(host) = &cli.upstream_host {
+                upstream.host = host.clone();
+            }
+            if let Some(port) = cli.upstream_port {
+                upstream.port = Some(port);
+            }
+        }
+
+        if let Some(endpoint) = &cli.otlp_endpoint {
+            self.tracing.otlp_endpoint = Some(endpoint.clone());
+        }
+
+        if let Some(secs) = cli.exit_after_unhealthy_secs {
+            self.health_check.exit_after_unhealthy_secs = Some(secs);
+        }
+
+        if let Some(upstream_type) = &cli.upstream_type {
+            // Keeps the rest of the route configuration (if any) e.g. the timeouts
+            self.routes
+                .entry(upstream_type.clone())
+                .or_insert_with(|| RouteConfig {
+                    upstream: DEFAULT_UPSTREAM.to_string(),
+                    timeouts: TimeoutsConfig::default(),
+                    fallback: None,
+                    shadow: None,
+                })
+                .upstream = DEFAULT_UPSTREAM.to_string();
+        }
+    }
+
+    /// Validates the configuration, collecting all the issues found rather than stopping at the
+    /// first one, so that those can be reported at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        for (name, upstream) in &self.upstreams {
+            if let Err(e) = build_upstream_uri(&upstream.host, upstream.port) {
+                errors.push(format!("upstreams.{name}: invalid upstream URI, {e}"));
+            }
+            if upstream.max_concurrent_requests == Some(0) {
+                errors.push(format!(
+                    "upstreams.{name}.max_concurrent_requests: must be greater than 0"
+                ));
+            }
+        }
+
+        if self.routes.is_empty() {
+            errors.push(
+                "routes: no routes configured, either set `--upstream-type` or define at least one route within the configuration file".to_string(),
+            );
+        }
+        for (name, pool) in &self.pools {
+            if self.upstreams.contains_key(name) {
+                errors.push(format!(
+                    "pools.{name}: the name is already used by an upstream"
+                ));
+            }
+            if pool.upstreams.is_empty() {
+                errors.push(format!("pools.{name}: no upstreams configured"));
+            }
+            for upstream in &pool.upstreams {
+                if !self.upstreams.contains_key(upstream) {
+                    errors.push(format!("pools.{name}: unknown upstream '{upstream}'"));
+                }
+            }
+            if pool.max_load_factor.is_nan() || pool.max_load_factor < 1.0 {
+                errors.push(format!("pools.{name}.max_load_factor: must be at least 1"));
+            }
+            if pool.scrape_interval_secs == 0 {
+                errors.push(format!(
+                    "pools.{name}.scrape_interval_secs: must be greater than 0"
+                ));
+            }
+        }
+
+        for (name, split) in &self.splits {
+            if self.upstreams.contains_key(name) || self.pools.contains_key(name) {
+                errors.push(format!(
+                    "splits.{name}: the name is already used by an upstream or a pool"
+                ));
+            }
+            if split
+                .variants
+                .values()
+                .map(|variant| u64::from(variant.weight))
+                .sum::<u64>()
+                == 0
+            {
+                errors.push(format!(
+                    "splits.{name}: no variants configured with a weight greater than 0"
+                ));
+            }
+            for (variant_name, variant) in &split.variants {
+                if !self.upstreams.contains_key(&variant.upstream)
+                    && !self.pools.contains_key(&variant.upstream)
+                {
+                    errors.push(format!(
+                        "splits.{name}.variants.{variant_name}: unknown upstream '{}'",
+                        variant.upstream
+                    ));
+                }
+            }
+            if split.sticky == Some(StickyKey::Header) && split.sticky_header.is_none() {
+                errors.push(format!(
+                    "splits.{name}.sticky_header: must be set with `sticky = \"header\"`"
+                ));
+            }
+        }
+
+        let is_target = |name: &String| {
+            self.upstreams.contains_key(name)
+                || self.pools.contains_key(name)
+                || self.splits.contains_key(name)
+        };
+        for (route, config) in &self.routes {
+            if !is_target(&config.upstream) {
+                errors.push(format!(
+                    "routes.{}: unknown upstream '{}'",
+                    route.as_str(),
+                    config.upstream
+                ));
+            }
+            if let Some(fallback) = &config.fallback
+                && !is_target(&fallback.upstream)
+            {
+                errors.push(format!(
+                    "routes.{}.fallback: unknown upstream '{}'",
+                    route.as_str(),
+                    fallback.upstream
+                ));
+            }
+            if let Some(shadow) = &config.shadow {
+                if !is_target(&shadow.upstream) {
+                    errors.push(format!(
+                        "routes.{}.shadow: unknown upstream '{}'",
+                        route.as_str(),
+                        shadow.upstream
+                    ));
+                }
+                if !(shadow.percent >= 0.0 && shadow.percent <= 100.0) {
+                    errors.push(format!(
+                        "routes.{}.shadow.percent: must be between 0 and 100",
+                        route.as_str()
+                    ));
+                }
+            }
+        }
+
+        for (name, model) in &self.models {
+            if !is_target(&model.upstream) {
+                errors.push(format!(
+                    "models.{name}: unknown upstream '{}'",
+                    model.upstream
+                ));
+            }
+            if let Some(fallback) = &model.fallback
+                && !is_target(&fallback.upstream)
+            {
+                errors.push(format!(
+                    "models.{name}.fallback: unknown upstream '{}'",
+                    fallback.upstream
+                ));
+            }
+        }
+
+        if self.auth.api_keys.iter().any(|key| key.trim().is_empty()) {
+            errors.push("auth.api_keys: API keys cannot be empty".to_string());
+        }
+
+        if self.limits.max_request_body_bytes == Some(0) {
+            errors.push("limits.max_request_body_bytes: must be greater than 0".to_string());
+        }
+
+        for (field, value) in [
+            ("requests_per_minute", self.rate_limit.requests_per_minute),
+            ("tokens_per_minute", self.rate_limit.tokens_per_minute),
+        ] {
+            if value == Some(0) {
+                errors.push(format!("rate_limit.{field}: must be greater than 0"));
+            }
+        }
+
+        if let Some(status) = self
+            .retry
+            .statuses
+            .iter()
+            .find(|status| axum::http::StatusCode::from_u16(**status).is_err())
+        {
+            errors.push(format!("retry.statuses: invalid status code {status}"));
+        }
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            errors.push(
+                "retry.initial_backoff_ms: must be lower than or equal to `max_backoff_ms`"
+                    .to_string(),
+            );
+        }
+
+        if !(self.circuit_breaker.error_rate > 0.0 && self.circuit_breaker.error_rate <= 1.0) {
+            errors.push("circuit_breaker.error_rate: must be between 0 and 1".to_string());
+        }
+        for (field, value) in [
+            (
+                "consecutive_failures",
+                self.circuit_breaker.consecutive_failures,
+            ),
+            (
+                "half_open_requests",
+                self.circuit_breaker.half_open_requests,
+            ),
+        ] {
+            if value == 0 {
+                errors.push(format!("circuit_breaker.{field}: must be greater than 0"));
+            }
+        }
+
+        if self.logging.level.parse::<tracing::Level>().is_err() {
+            errors.push(format!(
+                "logging.level: unknown log level '{}', expected one of trace, debug, info, warn or error",
+                self.logging.level
+            ));
+        }
+
+        if let Some(endpoint) = &self.tracing.otlp_endpoint
+            && !endpoint
+                .parse::<axum::http::Uri>()
+                .is_ok_and(|uri| uri.scheme_str() == Some("http"))
+        {
+            errors.push(format!(
+                "tracing.otlp_endpoint: invalid endpoint '{endpoint}', expected an `http://` URL e.g. http://localhost:4318"
+            ));
+        }
+
+        let health_check = &self.health_check;
+        if !health_check.path.starts_with('/') {
+            errors.push("health_check.path: must start with '/'".to_string());
+        }
+        for (field, value) in [
+            ("interval_secs", health_check.interval_secs),
+            ("timeout_secs", health_check.timeout_secs),
+            (
+                "unhealthy_threshold",
+                health_check.unhealthy_threshold.into(),
+            ),
+            ("healthy_threshold", health_check.healthy_threshold.into()),
+        ] {
+            if value == 0 {
+                errors.push(format!("health_check.{field}: must be greater than 0"));
+            }
+        }
+
+        #[cfg(not(unix))]
+        if self.server.host.starts_with("unix://") {
+            errors.push(
+                "server.host: Unix domain sockets are only supported on Unix platforms".to_string(),
+            );
+        }
+        if self.metrics.port == Some(self.server.port) && !self.server.host.starts_with("unix://") {
+            errors.push("metrics.port: must be different from server.port".to_string());
+        }
+
+        let cache = &self.cache;
+        for (field, value) in [
+            ("max_entries", cache.max_entries as u64),
+            ("ttl_secs", cache.ttl_secs),
+            ("max_entry_bytes", cache.max_entry_bytes as u64),
+        ] {
+            if value == 0 {
+                errors.push(format!("cache.{field}: must be greater than 0"));
+            }
+        }
+        if let Some(path) = &cache.disk_path
+            && !path.is_dir()
+        {
+            errors.push(format!(
+                "cache.disk_path: '{}' is not an existing directory",
+                path.display()
+            ));
+        }
+
+        for (field, value) in [
+            ("max_entries", self.embeddings_cache.max_entries as u64),
+            ("ttl_secs", self.embeddings_cache.ttl_secs),
+        ] {
+            if value == 0 {
+                errors.push(format!("embeddings_cache.{field}: must be greater than 0"));
+            }
+        }
+
+        if self.embeddings_batching.max_batch_size == 0 {
+            errors.push("embeddings_batching.max_batch_size: must be greater than 0".to_string());
+        }
+        if self.embeddings_batching.max_batch_tokens == Some(0) {
+            errors.push("embeddings_batching.max_batch_tokens: must be greater than 0".to_string());
+        }
+
+        let splitting = &self.embeddings_splitting;
+        for (field, value) in [
+            ("max_batch_size", splitting.max_batch_size as u64),
+            ("max_batch_tokens", splitting.max_batch_tokens.unwrap_or(1)),
+            ("max_concurrency", splitting.max_concurrency as u64),
+        ] {
+            if value == 0 {
+                errors.push(format!(
+                    "embeddings_splitting.{field}: must be greater than 0"
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    /// Returns the names of the upstreams (or pools) serving either a route or a model, including
+    /// the ones serving each variant of the traffic splits
+    pub fn routed_upstreams(&self) -> BTreeSet<&String> {
+        self.routes
+            .values()
+            .map(|route| &route.upstream)
+            .chain(self.models.values().map(|model| &model.upstream))
+            .flat_map(|name| match self.splits.get(name) {
+                Some(split) => split
+                    .variants
+                    .values()
+                    .map(|variant| &variant.upstream)
+                    .collect(),
+                None => vec![name],
+            })
+            .collect()
+    }
+
+    /// Returns the names of the upstreams within the pool, or the upstream itself if not a pool
+    pub fn pool_members<'a>(&'a self, name: &'a String) -> Vec<&'a String> {
+        match self.pools.get(name) {
+            Some(pool) => pool.upstreams.iter().collect(),
+            None => vec![name],
+        }
+    }
+
+    /// Resolves the upstream for a route, being the one defined for the `model` (if any) or the
+    /// default one for the route otherwise, along with the timeouts of the model overriding the
+    /// ones of the route. If that's a traffic split, then the variant is picked with the provided
+    /// `split` function, and if that's a pool, then the upstream is picked out of the pool with the
+    /// provided `pick` function.
+    pub fn resolve(
+        &self,
+        route: &UpstreamType,
+        model: Option<&str>,
+        pick: impl FnOnce(&str, &PoolConfig) -> Option<String>,
+        split: impl FnOnce(&str, &SplitConfig) -> Option<String>,
+    ) -> Option<ResolvedUpstream> {
+        let route = self.routes.get(route);
+        let route_timeouts = route.map(|route| route.timeouts).unwrap_or_default();
+        let shadow = route.and_then(|route| route.shadow.clone());
+        let (name, model, timeouts, fallback) = match model.and_then(|model| self.models.get(model))
+        {
+            Some(config) => (
+                &config.upstream,
+                config.model.clone(),
+                config.timeouts.or(route_timeouts),
+                config.fallback.clone(),
+            ),
+            None => {
+                let route = route?;
+                (
+                    &route.upstream,
+                    None,
+                    route_timeouts,
+                    route.fallback.clone(),
+                )
+            }
+        };
+
+        let mut upstream = self.resolve_target(name, model, timeouts, pick, split)?;
+        upstream.fallback = fallback;
+        upstream.shadow = shadow;
+        Some(upstream)
+    }
+
+    /// Resolves the shadow upstream of the upstream (if any), keeping the timeouts of the upstream
+    pub fn resolve_shadow(
+        &self,
+        upstream: &ResolvedUpstream,
+        pick: impl FnOnce(&str, &PoolConfig) -> Option<String>,
+        split: impl FnOnce(&str, &SplitConfig) -> Option<String>,
+    ) -> Option<ResolvedUpstream> {
+        let shadow = upstream.shadow.as_ref()?;
+        self.resolve_target(
+            &shadow.upstream,
+            shadow.model.clone().or(upstream.model.clone()),
+            upstream.timeouts,
+            pick,
+            split,
+        )
+    }
+
+    /// Resolves the fallback of the upstream (if any), keeping the timeouts of the upstream
+    pub fn resolve_fallback(
+        &self,
+        upstream: &ResolvedUpstream,
+        pick: impl FnOnce(&str, &PoolConfig) -> Option<String>,
+        split: impl FnOnce(&str, &SplitConfig) -> Option<String>,
+    ) -> Option<ResolvedUpstream> {
+        let fallback = upstream.fallback.as_ref()?;
+        self.resolve_target(
+            &fallback.upstream,
+            fallback.model.clone(),
+            upstream.timeouts,
+            pick,
+            split,
+        )
+    }
+
+    /// Resolves the upstream (or the one picked out of the pool) with the given name, or the one
+    /// of the variant picked out of the traffic split, whose model overrides the given one
+    fn resolve_target(
+        &self,
+        name: &String,
+        model: Option<String>,
+        timeouts: TimeoutsConfig,
+        pick: impl FnOnce(&str, &PoolConfig) -> Option<String>,
+        split: impl FnOnce(&str, &SplitConfig) -> Option<String>,
+    ) -> Option<ResolvedUpstream> {
+        let (name, model, variant) = match self.splits.get(name) {
+            Some(config) => {
+                let variant_name = split(name, config)?;
+                let variant = config.variants.get(&variant_name)?;
+                (
+                    &variant.upstream,
+                    variant.model.clone().or(model),
+                    Some((name.clone(), variant_name)),
+                )
+            }
+            None => (name, model, None),
+        };
+
+        let name = match self.pools.get(name) {
+            Some(pool) => pick(name, pool)?,
+            None => name.clone(),
+        };
+        let upstream = self.upstreams.get(&name)?;
+        Some(ResolvedUpstream {
+            uri: build_upstream_uri(&upstream.host, upstream.port).ok()?,
+            name,
+            model,
+            timeouts,
+            fallback: None,
+            variant,
+            shadow: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reports_all_errors() {
+        let config: Config = toml::from_str(
+            r#"
+            [upstreams.vllm]
+            host = "0.0.0.0"
+            port = 8000
+
+            [routes.chat-completions]
+            upstream = "sglang"
+
+            [models.phi]
+            upstream = "tgi"
+
+            [logging]
+            level = "verbose"
+            "#,
+        )
+        .unwrap();
+
+        let Err(ConfigError::Invalid(errors)) = config.validate() else {
+            panic!("expected the configuration to be invalid");
+        };
+        assert_eq!(
+            errors,
+            vec![
+                "routes.chat-completions: unknown upstream 'sglang'",
+                "models.phi: unknown upstream 'tgi'",
+                "logging.level: unknown log level 'verbose', expected one of trace, debug, info, \
+                 warn or error",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_redacted_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            [auth]
+            api_keys = ["sk-first", "sk-second"]
+            "#,
+        )
+        .unwrap();
+
+        let printed = config.to_redacted_toml().unwrap();
+        assert!(!printed.contains("sk-first") && !printed.contains("sk-second"));
+        let printed: Config = toml::from_str(&printed).unwrap();
+        assert_eq!(printed.auth.api_keys, vec![REDACTED, REDACTED]);
+    }
+
+    #[test]
+    fn test_resolve_model_alias() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+            upstreams:
+              vllm:
+                host: 0.0.0.0
+                port: 8000
+              tgi:
+                host: 0.0.0.0
+                port: 8080
+            routes:
+              chat-completions:
+                upstream: vllm
+                timeouts:
+                  first_byte_secs: 60
+                  idle_secs: 10
+            models:
+              phi:
+                upstream: tgi
+                model: microsoft/Phi-4
+                timeouts:
+                  idle_secs: 30
+                fallback:
+                  upstream: vllm
+                  model: microsoft/Phi-4-mini-instruct
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let upstream = config
+            .resolve(
+                &UpstreamType::ChatCompletions,
+                Some("phi"),
+                |_, _| None,
+                |_, _| None,
+            )
+            .unwrap();
+        assert_eq!(upstream.name, "tgi");
+        assert_eq!(upstream.model.as_deref(), Some("microsoft/Phi-4"));
+        // The timeouts of the model override the ones of the route
+        assert_eq!(upstream.timeouts.first_byte_secs, Some(60));
+        assert_eq!(upstream.timeouts.idle_secs, Some(30));
+
+        // The fallback keeps the timeouts, but has no fallback itself
+        let fallback = config
+            .resolve_fallback(&upstream, |_, _| None, |_, _| None)
+            .unwrap();
+        assert_eq!(fallback.name, "vllm");
+        assert_eq!(
+            fallback.model.as_deref(),
+            Some("microsoft/Phi-4-mini-instruct")
+        );
+        assert_eq!(fallback.timeouts.idle_secs, Some(30));
+        assert!(config
+            .resolve_fallback(&fallback, |_, _| None, |_, _| None)
+            .is_none());
+
+        let upstream = config
+            .resolve(
+                &UpstreamType::ChatCompletions,
+                Some("other"),
+                |_, _| None,
+                |_, _| None,
+            )
+            .unwrap();
+        assert_eq!(upstream.name, "vllm");
+        assert_eq!(upstream.model, None);
+        assert_eq!(upstream.timeouts.idle_secs, Some(10));
+        assert!(upstream.fallback.is_none());
+
+        assert!(config
+            .resolve(&UpstreamType::Embeddings, None, |_, _| None, |_, _| None)
+            .is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use axum::http::Uri;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection, HttpConnector},
+    rt::TokioIo,
+};
+#[cfg(unix)]
+use hyperlocal::{UnixConnector, UnixStream};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+/// Custom error type for the connector, as both the TCP and the Unix domain socket connectors
+/// report their errors with different types
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+tokio::task_local! {
+    /// The connect timeout of the request being sent, scoped to the request as the connections are
+    /// established by the client on demand (i.e. when there's no idle connection in the pool)
+    pub static CONNECT_TIMEOUT: Duration;
+}
+
+/// Error reported when the connection to the upstream is not established within the timeout
+#[derive(Debug, thiserror::Error)]
+#[error("connection timed out after {0:?}")]
+pub struct ConnectTimeout(pub Duration);
+
+/// The connection being established by the `UpstreamConnector`
+type Connecting = Pin<Box<dyn Future<Output = Result<UpstreamStream, BoxError>> + Send>>;
+
+/// Connector that dispatches the connection to either the `HttpConnector` (TCP) or the
+/// `UnixConnector` (Unix domain socket) based on the scheme of the upstream URI, so that
+/// `unix://` URIs (with the hex-encoded socket path as the host, as defined by `hyperlocal`) are
+/// handled over a Unix domain socket, and everything else over TCP.
+#[derive(Clone, Debug)]
+pub struct UpstreamConnector {
+    http: HttpConnector,
+    #[cfg(unix)]
+    unix: UnixConnector,
+}
+
+impl UpstreamConnector {
+    pub fn new() -> Self {
+        Self {
+            http: HttpConnector::new(),
+            #[cfg(unix)]
+            unix: UnixConnector,
+        }
+    }
+}
+
+/// Bounds the connection with the connect timeout of the request being sent, if any
+fn with_connect_timeout(connecting: Connecting) -> Connecting {
+    match CONNECT_TIMEOUT.try_with(|timeout| *timeout) {
+        Ok(timeout) => Box::pin(async move {
+            tokio::time::timeout(timeout, connecting)
+                .await
+                .map_err(|_| ConnectTimeout(timeout))?
+        }),
+        Err(_) => connecting,
+    }
+}
+
+impl Service<Uri> for UpstreamConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = Connecting;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        #[cfg(unix)]
+        if uri.scheme_str() == Some("unix") {
+            let connecting = self.unix.call(uri);
+            return with_connect_timeout(Box::pin(async move {
+                connecting
+                    .await
+                    .map(UpstreamStream::Unix)
+                    .map_err(Into::into)
+            }));
+        }
+
+        let connecting = self.http.call(uri);
+        with_connect_timeout(Box::pin(async move {
+            connecting
+                .await
+                .map(UpstreamStream::Tcp)
+                .map_err(Into::into)
+        }))
+    }
+}
+
+/// Connection established by the `UpstreamConnector`, either over TCP or over an Unix domain
+/// socket
+#[derive(Debug)]
+pub enum UpstreamStream {
+    Tcp(TokioIo<TcpStream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Write::poll_write(Pin::new(stream), cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Write::poll_flush(Pin::new(stream), cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Write::poll_shutdown(Pin::new(stream), cx),
+        }
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.connected(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_connects_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+
+        let stream = UpstreamConnector::new()
+            .call(uri.parse().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(stream, UpstreamStream::Tcp(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_connects_over_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!(
+            "azure-openai-proxy-connector-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket_path);
+        let _listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+
+        // The `unix://` hosts are dispatched to the socket, whatever the port of the upstream
+        let uri = crate::utils::build_upstream_uri(
+            &format!("unix://{}", socket_path.display()),
+            Some(8080),
+        )
+        .unwrap();
+        let stream = UpstreamConnector::new().call(uri).await;
+        std::fs::remove_file(&socket_path).unwrap();
+        assert!(matches!(stream.unwrap(), UpstreamStream::Unix(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connect_timeout() {
+        let timeout = Duration::from_secs(10);
+        let connecting = CONNECT_TIMEOUT.sync_scope(timeout, || {
+            with_connect_timeout(Box::pin(std::future::pending()))
+        });
+        let start = tokio::time::Instant::now();
+        let error = connecting.await.unwrap_err();
+        assert_eq!(start.elapsed(), timeout);
+        assert!(error.downcast_ref::<ConnectTimeout>().is_some());
+
+        // Outside of the scope of a request, the connection is not bounded
+        let connecting = with_connect_timeout(Box::pin(std::future::pending()));
+        assert!(tokio::time::timeout(Duration::from_secs(3600), connecting)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{
+    config::EmbeddingsBatchingConfig,
+    embeddings_response,
+    errors::AzureError,
+    metrics::Metrics,
+    middlewares::{rate_limit::BYTES_PER_TOKEN, request_id::REQUEST_ID},
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{response::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{oneshot, Notify};
+use tracing::{Instrument, Span};
+
+/// A request waiting within a batch, along with where to send its share of the response to
+#[derive(Debug)]
+struct Caller {
+    inputs: Vec<String>,
+    tokens: u64,
+    tx: oneshot::Sender<Result<Response, AzureError>>,
+}
+
+/// A batch collecting the concurrent requests until either the window elapses or it's full
+#[derive(Debug, Default)]
+struct Batch {
+    callers: Mutex<Vec<Caller>>,
+    /// Notified once the batch is full, so that it's sent before the window elapses
+    full: Notify,
+}
+
+/// The open batches of embeddings requests, indexed by the parameters (e.g. the model) shared by
+/// the requests within each batch
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingsBatcher {
+    batches: Arc<Mutex<HashMap<String, Arc<Batch>>>>,
+}
+
+/// Returns the number of tokens of the input, estimated from its size
+pub fn estimate_tokens(input: &str) -> u64 {
+    (input.len() as u64).div_ceil(BYTES_PER_TOKEN)
+}
+
+impl EmbeddingsBatcher {
+    /// Adds the inputs to the open batch with the same key, or opens a new one if there's none or
+    /// the inputs don't fit within it, and returns the share of the response to the batch for the
+    /// inputs. A new batch is sent with the `send` function of the request that opened it (i.e.
+    /// to its upstream and with its headers) once either the window elapses or it's full, in the
+    /// background, so that it's sent even if that request is cancelled.
+    pub async fn submit<F, Fut>(
+        &self,
+        config: &EmbeddingsBatchingConfig,
+        metrics: &Metrics,
+        key: String,
+        inputs: Vec<String>,
+        send: F,
+    ) -> Result<Response, AzureError>
+    where
+        F: FnOnce(Vec<String>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Response, AzureError>> + Send + 'static,
+    {
+        let tokens = inputs.iter().map(|input| estimate_tokens(input)).sum();
+        let is_full = |size: usize, tokens: u64| {
+            size >= config.max_batch_size
+                || config.max_batch_tokens.is_some_and(|max| tokens >= max)
+        };
+        // The requests that would fill a batch on their own are sent right away
+        if is_full(inputs.len(), tokens) {
+            return send(inputs).await;
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let caller = Caller { inputs, tokens, tx };
+        let opened = {
+            let mut batches = self.batches.lock().unwrap();
+            let joined = match batches.get(&key) {
+                Some(batch) => {
+                    let mut callers = batch.callers.lock().unwrap();
+                    let size = callers
+                        .iter()
+                        .map(|caller| caller.inputs.len())
+                        .sum::<usize>()
+                        + caller.inputs.len();
+                    let tokens =
+                        callers.iter().map(|caller| caller.tokens).sum::<u64>() + caller.tokens;
+                    let fits = size <= config.max_batch_size
+                        && config.max_batch_tokens.is_none_or(|max| tokens <= max);
+                    if fits {
+                        callers.push(caller);
+                        Ok(is_full(size, tokens))
+                    } else {
+                        Err(caller)
+                    }
+                }
+                None => Err(caller),
+            };
+
+            match joined {
+                // A full batch is sent right away, and the next requests open a new one
+                Ok(full) => {
+                    if full && let Some(batch) = batches.remove(&key) {
+                        batch.full.notify_one();
+                    }
+                    None
+                }
+                // If the inputs don't fit, the open batch is sent right away, and a new one is
+                // opened with those
+                Err(caller) => {
+                    if let Some(batch) = batches.remove(&key) {
+                        batch.full.notify_one();
+                    }
+                    let batch = Arc::new(Batch::default());
+                    batch.callers.lock().unwrap().push(caller);
+                    batches.insert(key.clone(), batch.clone());
+                    Some(batch)
+                }
+            }
+        };
+        let Some(batch) = opened else {
+            return rx.await.unwrap_or_else(|_| Err(dropped()));
+        };
+
+        let window = Duration::from_millis(config.window_ms);
+        let flush = self
+            .clone()
+            .flush(key, batch, window, metrics.clone(), send)
+            .instrument(Span::current());
+        // The batch is sent in the context of the request that opened it
+        let request_id = REQUEST_ID.try_with(|request_id| request_id.clone()).ok();
+        tokio::spawn(async move {
+            match request_id {
+                Some(request_id) => REQUEST_ID.scope(request_id, flush).await,
+                None => flush.await,
+            }
+        });
+
+        rx.await.unwrap_or_else(|_| Err(dropped()))
+    }
+
+    /// Waits for the batch to be either full or for the window to elapse, and then sends it and
+    /// splits the response back to the requests within it
+    async fn flush<F, Fut>(
+        self,
+        key: String,
+        batch: Arc<Batch>,
+        window: Duration,
+        metrics: Metrics,
+        send: F,
+    ) where
+        F: FnOnce(Vec<String>) -> Fut,
+        Fut: Future<Output = Result<Response, AzureError>>,
+    {
+        let _ = tokio::time::timeout(window, batch.full.notified()).await;
+        {
+            let mut batches = self.batches.lock().unwrap();
+            if batches
+                .get(&key)
+                .is_some_and(|open| Arc::ptr_eq(open, &batch))
+            {
+                batches.remove(&key);
+            }
+        }
+        // Once removed, no more requests can join the batch
+        let callers = std::mem::take(&mut *batch.callers.lock().unwrap());
+        metrics.record_embeddings_batch(callers.len());
+
+        let inputs = callers
+            .iter()
+            .flat_map(|caller| caller.inputs.iter().cloned())
+            .collect();
+        let res = send(inputs).await.unwrap_or_else(|e| e.into_response());
+        let (parts, body) = res.into_parts();
+        let body = match embeddings_response::read(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                for caller in callers {
+                    let _ = caller.tx.send(Err(e.clone()));
+                }
+                return;
+            }
+        };
+
+        let shares = callers
+            .iter()
+            .map(|caller| (caller.inputs.len(), caller.tokens))
+            .collect::<Vec<_>>();
+        let responses = split(&parts, &body, &shares);
+        for (caller, res) in callers.into_iter().zip(responses) {
+            let _ = caller.tx.send(res);
+        }
+    }
+}
+
+fn dropped() -> AzureError {
+    AzureError::Upstream(
+        StatusCode::BAD_GATEWAY,
+        "The batch the request was within was dropped".to_string(),
+    )
+}
+
+/// Splits the response to a batch into the responses to the requests within it, given the number
+/// of inputs and the estimated tokens of each, where the embeddings are re-indexed from 0 for each
+/// request and the `usage` is split in proportion to the estimated tokens; the error responses are
+/// returned as is to all the requests
+fn split(
+    parts: &Parts,
+    body: &Bytes,
+    shares: &[(usize, u64)],
+) -> Vec<Result<Response, AzureError>> {
+    if parts.status != StatusCode::OK {
+        return shares
+            .iter()
+            .map(|_| {
+                Ok(Response::from_parts(
+                    parts.clone(),
+                    Body::from(body.clone()),
+                ))
+            })
+            .collect();
+    }
+
+    let mut response = match embeddings_response::parse(body) {
+        Ok(response) => response,
+        Err(e) => return shares.iter().map(|_| Err(e.clone())).collect(),
+    };
+    // The embeddings are taken out of the response so that those are not copied into every share
+    let total = shares.iter().map(|(inputs, _)| inputs).sum::<usize>();
+    let mut embeddings = embeddings_response::take_embeddings(&mut response, total).into_iter();
+
+    let total_tokens = shares.iter().map(|(_, tokens)| tokens).sum::<u64>().max(1);
+    let usage = |field: &str, cumulative: u64| {
+        let reported = response
+            .get("usage")
+            .and_then(|usage| usage.get(field))
+            .and_then(Value::as_u64)?;
+        // Rounded on the cumulative tokens, so that the shares add up to the reported tokens
+        Some((reported * cumulative + total_tokens / 2) / total_tokens)
+    };
+
+    let mut cumulative = 0;
+    shares
+        .iter()
+        .map(|&(inputs, tokens)| {
+            let before = cumulative;
+            cumulative += tokens;
+
+            let mut share = response.clone();
+            let data = embeddings_response::data(embeddings.by_ref().take(inputs))?;
+            share.insert("data".to_string(), data);
+            if let Some(Value::Object(shared_usage)) = share.get_mut("usage") {
+                for field in ["prompt_tokens", "total_tokens"] {
+                    if let (Some(from), Some(to)) = (usage(field, before), usage(field, cumulative))
+                    {
+                        shared_usage.insert(field.to_string(), json!(to - from));
+                    }
+                }
+            }
+            embeddings_response::respond(parts.clone(), &share)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, Json};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn upstream(inputs: &[String]) -> Response {
+        let data = inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| json!({"index": index, "embedding": [input.len()]}))
+            .collect::<Vec<_>>();
+        Json(json!({"data": data, "usage": {"prompt_tokens": 10, "total_tokens": 10}}))
+            .into_response()
+    }
+
+    async fn body(res: Result<Response, AzureError>) -> Value {
+        let body = to_bytes(res.unwrap().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_submit_batches_concurrent_requests() {
+        let batcher = EmbeddingsBatcher::default();
+        let metrics = Metrics::default();
+        let config = EmbeddingsBatchingConfig {
+            window_ms: 50,
+            max_batch_size: 3,
+            ..Default::default()
+        };
+        let sent = Arc::new(AtomicUsize::new(0));
+        let submit = |inputs: &[&str]| {
+            let sent = sent.clone();
+            batcher.submit(
+                &config,
+                &metrics,
+                "bge".to_string(),
+                inputs.iter().map(|input| input.to_string()).collect(),
+                move |inputs: Vec<String>| async move {
+                    sent.fetch_add(1, Ordering::Relaxed);
+                    Ok(upstream(&inputs))
+                },
+            )
+        };
+
+        // The first two requests fill the batch, so the third one is sent within another batch
+        let (first, second, third) =
+            tokio::join!(submit(&["a"]), submit(&["bb", "cccc"]), submit(&["d"]));
+        assert_eq!(sent.load(Ordering::Relaxed), 2);
+
+        let (first, second, third) = (body(first).await, body(second).await, body(third).await);
+        assert_eq!(
+            first["data"],
+            json!([{"object": "embedding", "index": 0, "embedding": [1]}])
+        );
+        assert_eq!(
+            second["data"][1],
+            json!({"object": "embedding", "index": 1, "embedding": [4]})
+        );
+        assert_eq!(third["data"][0]["embedding"], json!([1]));
+        // The usage is split in proportion to the estimated tokens (i.e. 1 and 2), adding up
+        assert_eq!(first["usage"]["prompt_tokens"], 3);
+        assert_eq!(second["usage"]["prompt_tokens"], 7);
+        assert_eq!(third["usage"]["total_tokens"], 10);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::{
+    cache::{Entries, CACHE},
+    config::{EmbeddingsCacheConfig, ResolvedUpstream},
+    embeddings_response,
+    errors::AzureError,
+    middlewares::auth::credentials_hash,
+    schemas::embeddings::EmbeddingsRequest,
+    upstream::Fallback,
+};
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+/// An embedding held by the cache, as the numbers themselves rather than as JSON values (which take
+/// several times the memory), or as the Base64 string if requested via the `encoding_format`
+#[derive(Debug, Clone)]
+enum Embedding {
+    Floats(Arc<[f32]>),
+    Integers(Arc<[i32]>),
+    Base64(Arc<str>),
+}
+
+impl Embedding {
+    /// Returns the embedding to cache, if it's either an array of numbers or a Base64 string
+    fn new(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(base64) => Some(Self::Base64(base64.as_str().into())),
+            Value::Array(values) if values.iter().all(Value::is_i64) => values
+                .iter()
+                .map(|value| value.as_i64().and_then(|value| i32::try_from(value).ok()))
+                .collect::<Option<_>>()
+                .map(Self::Integers),
+            Value::Array(values) => values
+                .iter()
+                .map(|value| value.as_f64().map(|value| value as f32))
+                .collect::<Option<_>>()
+                .map(Self::Floats),
+            _ => None,
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            Self::Floats(floats) => json!(floats[..]),
+            Self::Integers(integers) => json!(integers[..]),
+            Self::Base64(base64) => json!(base64[..]),
+        }
+    }
+}
+
+/// The LRU cache of the embeddings of each input, held in memory up to a maximum number of
+/// embeddings, so that the inputs embedded over and over (e.g. the chunks re-indexed by a RAG
+/// pipeline) are only sent to the upstream once
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingsCache {
+    entries: Arc<Mutex<Entries<Embedding>>>,
+}
+
+impl EmbeddingsCache {
+    /// Looks up the embedding of each input of the request, keyed on the input along with the
+    /// rest of the request (i.e. the requested model, `input_type`, `dimensions`, etc.), the model
+    /// served by the upstream, the variant of the traffic split (if any) and the hash of the
+    /// credentials forwarded to the upstream, where the inputs missing from the cache (or all of
+    /// those, if `lookup` is disabled) are deduplicated
+    pub fn lookup(
+        &self,
+        config: &EmbeddingsCacheConfig,
+        request: &EmbeddingsRequest,
+        upstream: &ResolvedUpstream,
+        headers: &HeaderMap,
+        lookup: bool,
+    ) -> CachedInputs {
+        let variant = upstream
+            .variant
+            .as_ref()
+            .map(|(split, variant)| format!("{split}/{variant}"))
+            .unwrap_or_default();
+        let parameters = format!(
+            "{}\n{}\n{variant}\n{:016x}",
+            request.parameters(),
+            upstream.model.as_deref().unwrap_or_default(),
+            credentials_hash(headers)
+        );
+        let now = SystemTime::now();
+        let mut entries = self.entries.lock().unwrap();
+        let (mut inputs, mut misses, mut miss_inputs) = (Vec::new(), Vec::new(), Vec::new());
+        let mut miss_indices = HashMap::new();
+        for input in request.inputs() {
+            let key = format!("{parameters}\n{input}");
+            inputs.push(match lookup.then(|| entries.get(&key, now)).flatten() {
+                Some(embedding) => Ok(embedding.to_value()),
+                None => Err(*miss_indices.entry(key).or_insert_with_key(|key| {
+                    misses.push(key.clone());
+                    miss_inputs.push(input.to_string());
+                    misses.len() - 1
+                })),
+            });
+        }
+
+        CachedInputs {
+            cache: self.clone(),
+            config: config.clone(),
+            model: request.model.clone(),
+            inputs,
+            misses,
+            miss_inputs,
+        }
+    }
+}
+
+/// The inputs of an embeddings request looked up in the cache
+pub struct CachedInputs {
+    cache: EmbeddingsCache,
+    config: EmbeddingsCacheConfig,
+    model: String,
+    /// The embedding of each input if cached, or the index of the input within the misses
+    inputs: Vec<Result<Value, usize>>,
+    /// The keys of the distinct inputs missing from the cache
+    misses: Vec<String>,
+    /// The distinct inputs missing from the cache, to be sent to the upstream
+    miss_inputs: Vec<String>,
+}
+
+impl CachedInputs {
+    /// Returns the distinct inputs missing from the cache, if those differ from the inputs of the
+    /// request (i.e. some were either cached or repeated), as otherwise the request is sent as is
+    pub fn misses(&self) -> Option<&[String]> {
+        (self.miss_inputs.len() != self.inputs.len()).then_some(&self.miss_inputs)
+    }
+
+    /// Returns the result of the lookup, i.e. `hit` if all the inputs were cached, `miss` if none
+    /// of those were, and `partial` otherwise
+    pub fn result(&self) -> &'static str {
+        let hits = self.inputs.iter().filter(|input| input.is_ok()).count();
+        match hits {
+            0 => "miss",
+            hits if hits == self.inputs.len() => "hit",
+            _ => "partial",
+        }
+    }
+
+    /// Returns the response with all the embeddings served from the cache, if all the inputs were
+    /// cached, reporting no tokens used as none were processed by the upstream
+    pub fn response(&self) -> Option<Response> {
+        let data = self
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(index, input)| {
+                let embedding = input.as_ref().ok()?;
+                Some(json!({"object": "embedding", "index": index, "embedding": embedding}))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        let mut res = Json(json!({
+            "object": "list",
+            "data": data,
+            "model": self.model,
+            "usage": {"prompt_tokens": 0, "total_tokens": 0},
+        }))
+        .into_response();
+        res.headers_mut()
+            .insert(CACHE, HeaderValue::from_static("hit"));
+        Some(res)
+    }
+
+    /// Caches the embeddings of the inputs sent to the upstream, and reassembles those with the
+    /// cached ones in the order of the inputs of the request, keeping the `usage` reported by the
+    /// upstream, i.e. the tokens of the inputs that were not cached
+    pub async fn merge(self, res: Response) -> Result<Response, AzureError> {
+        let (mut parts, body) = res.into_parts();
+        parts
+            .headers
+            .insert(CACHE, HeaderValue::from_static(self.result()));
+        if parts.status != StatusCode::OK {
+            return Ok(Response::from_parts(parts, body));
+        }
+
+        let mut response = embeddings_response::parse(&embeddings_response::read(body).await?)?;
+        let computed = embeddings_response::take_embeddings(&mut response, self.misses.len());
+
+        // The embeddings computed by the fallback are not cached, as another model may have
+        // computed those
+        if parts.extensions.get::<Fallback>().is_none() {
+            let expires_at = SystemTime::now() + Duration::from_secs(self.config.ttl_secs);
+            let mut entries = self.cache.entries.lock().unwrap();
+            for (key, embedding) in self.misses.into_iter().zip(&computed) {
+                if let Some(embedding) = embedding.as_ref().and_then(Embedding::new) {
+                    entries.insert(key, embedding, expires_at, self.config.max_entries);
+                }
+            }
+        }
+
+        let embeddings = self.inputs.into_iter().map(|input| match input {
+            Ok(embedding) => Some(embedding),
+            Err(miss) => computed[miss].clone(),
+        });
+        response.insert("data".to_string(), embeddings_response::data(embeddings)?);
+        embeddings_response::respond(parts, &response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::to_bytes, http::HeaderName};
+
+    fn request(input: Value) -> EmbeddingsRequest {
+        serde_json::from_value(json!({"model": "bge", "input": input, "dimensions": 2})).unwrap()
+    }
+
+    fn upstream() -> ResolvedUpstream {
+        ResolvedUpstream {
+            name: "tei".to_string(),
+            uri: "http://localhost:8080".parse().unwrap(),
+            model: None,
+            timeouts: Default::default(),
+            fallback: None,
+            variant: None,
+            shadow: None,
+        }
+    }
+
+    fn response(data: Value) -> Response {
+        Json(json!({
+            "object": "list",
+            "data": data,
+            "model": "bge",
+            "usage": {"prompt_tokens": 2, "total_tokens": 2}
+        }))
+        .into_response()
+    }
+
+    #[tokio::test]
+    async fn test_lookup_and_merge() {
+        let cache = EmbeddingsCache::default();
+        let config = EmbeddingsCacheConfig::default();
+        let (upstream, headers) = (upstream(), HeaderMap::new());
+        let lookup =
+            |request: &EmbeddingsRequest| cache.lookup(&config, request, &upstream, &headers, true);
+
+        // Repeated inputs are only sent once
+        let cached = lookup(&request(json!(["a", "b", "a"])));
+        assert_eq!(cached.result(), "miss");
+        assert_eq!(
+            cached.misses(),
+            Some(&["a".to_string(), "b".to_string()][..])
+        );
+        let res = response(json!([
+            {"object": "embedding", "index": 1, "embedding": [0.0, 1.0]},
+            {"object": "embedding", "index": 0, "embedding": [1.0, 0.0]}
+        ]));
+        let res = cached.merge(res).await.unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["data"][0]["embedding"], json!([1.0, 0.0]));
+        assert_eq!(body["data"][1]["embedding"], json!([0.0, 1.0]));
+        assert_eq!(
+            body["data"][2],
+            json!({"object": "embedding", "index": 2, "embedding": [1.0, 0.0]})
+        );
+        assert_eq!(body["usage"]["prompt_tokens"], 2);
+
+        // Only the new inputs are sent, and the other parameters are part of the key
+        let cached = lookup(&request(json!(["c", "b"])));
+        assert_eq!(cached.result(), "partial");
+        assert_eq!(cached.misses(), Some(&["c".to_string()][..]));
+        let other_dimensions = serde_json::from_value::<EmbeddingsRequest>(
+            json!({"model": "bge", "input": "a", "dimensions": 3}),
+        )
+        .unwrap();
+        assert_eq!(lookup(&other_dimensions).result(), "miss");
+
+        // All the inputs are cached, so served without the upstream
+        let cached = lookup(&request(json!("a")));
+        assert_eq!(cached.result(), "hit");
+        let res = cached.response().unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["data"][0]["embedding"], json!([1.0, 0.0]));
+        assert_eq!(body["usage"]["prompt_tokens"], 0);
+
+        // Nor across the models served, nor the credentials forwarded
+        let rewritten = ResolvedUpstream {
+            model: Some("BAAI/bge-m3".to_string()),
+            ..upstream.clone()
+        };
+        let cached = cache.lookup(&config, &request(json!("a")), &rewritten, &headers, true);
+        assert_eq!(cached.result(), "miss");
+        let other_key = HeaderMap::from_iter([(
+            HeaderName::from_static("api-key"),
+            HeaderValue::from_static("another-key"),
+        )]);
+        let cached = cache.lookup(&config, &request(json!("a")), &upstream, &other_key, true);
+        assert_eq!(cached.result(), "miss");
+    }
+
+    #[tokio::test]
+    async fn test_merge_from_fallback_is_not_cached() {
+        let cache = EmbeddingsCache::default();
+        let config = EmbeddingsCacheConfig::default();
+        let (upstream, headers) = (upstream(), HeaderMap::new());
+        let lookup =
+            |input: &str| cache.lookup(&config, &request(json!(input)), &upstream, &headers, true);
+
+        let mut res = response(json!([{"object": "embedding", "index": 0, "embedding": [1, 0]}]));
+        res.extensions_mut().insert(Fallback);
+        lookup("a").merge(res).await.unwrap();
+        assert_eq!(lookup("a").result(), "miss");
+
+        // The integer embeddings (e.g. `int8`) are kept as integers
+        let res = response(json!([{"object": "embedding", "index": 0, "embedding": [1, 0]}]));
+        lookup("a").merge(res).await.unwrap();
+        let res = lookup("a").response().unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["data"][0]["embedding"], json!([1, 0]));
+    }
+}
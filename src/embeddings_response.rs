@@ -0,0 +1,76 @@
+use crate::errors::AzureError;
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    http::{header::CONTENT_LENGTH, response::Parts, StatusCode},
+    response::Response,
+};
+use serde_json::{json, Map, Value};
+use std::fmt::Display;
+
+/// Maximum size in bytes of the embeddings responses of the upstream buffered to rework those,
+/// i.e. to cache, split or merge the embeddings within those
+const MAX_RESPONSE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Returns the error for an embeddings response of the upstream that can't be reworked
+pub fn invalid(e: impl Display) -> AzureError {
+    AzureError::Upstream(
+        StatusCode::BAD_GATEWAY,
+        format!("Invalid embeddings response from the upstream: {e}"),
+    )
+}
+
+/// Buffers the body of the embeddings response of the upstream
+pub async fn read(body: Body) -> Result<Bytes, AzureError> {
+    to_bytes(body, MAX_RESPONSE_BYTES).await.map_err(invalid)
+}
+
+/// Parses the body of the embeddings response of the upstream as a JSON object
+pub fn parse(body: &[u8]) -> Result<Map<String, Value>, AzureError> {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(response)) => Ok(response),
+        Ok(_) => Err(invalid("not a JSON object")),
+        Err(e) => Err(invalid(e)),
+    }
+}
+
+/// Takes the embeddings out of the `data` of the response, matched to the given number of inputs
+/// by their `index` if any, or by their position otherwise, where the inputs without an embedding
+/// are left as `None`
+pub fn take_embeddings(response: &mut Map<String, Value>, inputs: usize) -> Vec<Option<Value>> {
+    let mut embeddings = vec![None; inputs];
+    if let Some(Value::Array(data)) = response.remove("data") {
+        for (position, mut item) in data.into_iter().enumerate() {
+            let index = item
+                .get("index")
+                .and_then(Value::as_u64)
+                .map_or(position, |index| index as usize);
+            if let (Some(slot), Some(embedding)) =
+                (embeddings.get_mut(index), item.get_mut("embedding"))
+            {
+                *slot = Some(embedding.take());
+            }
+        }
+    }
+    embeddings
+}
+
+/// Returns the `data` of a response with the embeddings indexed from 0, failing if any is missing
+pub fn data(embeddings: impl IntoIterator<Item = Option<Value>>) -> Result<Value, AzureError> {
+    embeddings
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            Some(json!({"object": "embedding", "index": index, "embedding": embedding?}))
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(Value::Array)
+        .ok_or_else(|| invalid("missing the embeddings of some inputs"))
+}
+
+/// Returns the reworked response, along with the status and the headers of the original one
+pub fn respond(mut parts: Parts, response: &Map<String, Value>) -> Result<Response, AzureError> {
+    // The body is re-serialized, so its length is computed again
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = serde_json::to_vec(response).map_err(invalid)?;
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
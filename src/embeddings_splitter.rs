@@ -0,0 +1,235 @@
+use crate::{
+    config::EmbeddingsSplittingConfig, embeddings_batcher::estimate_tokens, embeddings_response,
+    errors::AzureError, upstream::Fallback,
+};
+use axum::{
+    http::{response::Parts, StatusCode},
+    response::Response,
+};
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Map, Value};
+use std::future::Future;
+
+/// Splits the inputs into consecutive sub-batches within both the maximum number of inputs and
+/// the maximum number of estimated tokens, where an input exceeding the latter on its own is sent
+/// alone within its sub-batch
+fn sub_batches(config: &EmbeddingsSplittingConfig, inputs: Vec<String>) -> Vec<Vec<String>> {
+    let max_tokens = config.max_batch_tokens.unwrap_or(u64::MAX);
+    let mut sub_batches: Vec<Vec<String>> = Vec::new();
+    let mut tokens = 0;
+    for input in inputs {
+        let input_tokens = estimate_tokens(&input);
+        match sub_batches.last_mut() {
+            Some(sub_batch)
+                if sub_batch.len() < config.max_batch_size
+                    && tokens + input_tokens <= max_tokens =>
+            {
+                tokens += input_tokens;
+                sub_batch.push(input);
+            }
+            _ => {
+                tokens = input_tokens;
+                sub_batches.push(vec![input]);
+            }
+        }
+    }
+    sub_batches
+}
+
+/// Sends the inputs to the upstream via `send`, split into sub-batches if those exceed the limits
+/// (with up to `max_concurrency` sub-batches in flight at once), and merges the responses to the
+/// sub-batches back into a single one, with the embeddings indexed in the order of the inputs and
+/// the `usage` summed up; the first error response to a sub-batch (if any) is returned as is,
+/// cancelling the sub-batches still in flight, and it fails if only some sub-batches were served by
+/// the fallback
+pub async fn send_in_sub_batches<F, Fut>(
+    config: &EmbeddingsSplittingConfig,
+    inputs: Vec<String>,
+    send: F,
+) -> Result<Response, AzureError>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Response, AzureError>>,
+{
+    if !config.enabled {
+        return send(inputs).await;
+    }
+    let mut sub_batches = sub_batches(config, inputs);
+    if sub_batches.len() <= 1 {
+        return send(sub_batches.pop().unwrap_or_default()).await;
+    }
+
+    tracing::info!(
+        "Splitting the embeddings request into {} sub-batches",
+        sub_batches.len()
+    );
+    let mut responses = stream::iter(sub_batches)
+        .map(|sub_batch| {
+            let size = sub_batch.len();
+            let res = send(sub_batch);
+            async move { (size, res.await) }
+        })
+        .buffered(config.max_concurrency);
+
+    let (mut merged, mut data) = (None::<(Parts, _)>, Vec::new());
+    while let Some((size, res)) = responses.next().await {
+        let res = res?;
+        if res.status() != StatusCode::OK {
+            return Ok(res);
+        }
+
+        let (parts, body) = res.into_parts();
+        let mut response = embeddings_response::parse(&embeddings_response::read(body).await?)?;
+        // The embeddings are re-indexed after the inputs of the previous sub-batches
+        data.extend(embeddings_response::take_embeddings(&mut response, size));
+
+        match &mut merged {
+            None => merged = Some((parts, response)),
+            Some((merged_parts, merged)) => {
+                // Each sub-batch may fail over on its own, but the embeddings of different models
+                // are not comparable, so those can't be merged into a single response
+                let fallback = |parts: &Parts| parts.extensions.get::<Fallback>().is_some();
+                if fallback(merged_parts) != fallback(&parts) {
+                    return Err(embeddings_response::invalid(
+                        "the sub-batches were served by different models",
+                    ));
+                }
+                sum_usage(merged, &response);
+            }
+        }
+    }
+
+    let (parts, mut response) =
+        merged.ok_or_else(|| embeddings_response::invalid("no response to any sub-batch"))?;
+    response.insert("data".to_string(), embeddings_response::data(data)?);
+    embeddings_response::respond(parts, &response)
+}
+
+/// Adds the tokens reported within the `usage` of the response to those of the merged response
+fn sum_usage(merged: &mut Map<String, Value>, response: &Map<String, Value>) {
+    let Some(Value::Object(usage)) = merged.get_mut("usage") else {
+        return;
+    };
+    for field in ["prompt_tokens", "total_tokens"] {
+        let tokens = response
+            .get("usage")
+            .and_then(|usage| usage.get(field))
+            .and_then(Value::as_u64);
+        if let (Some(total), Some(tokens)) = (usage.get(field).and_then(Value::as_u64), tokens) {
+            usage.insert(field.to_string(), json!(total + tokens));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::to_bytes,
+        response::{IntoResponse, Json},
+    };
+    use std::sync::Mutex;
+
+    fn inputs(inputs: &[&str]) -> Vec<String> {
+        inputs.iter().map(|input| input.to_string()).collect()
+    }
+
+    #[test]
+    fn test_sub_batches() {
+        let config = EmbeddingsSplittingConfig {
+            enabled: true,
+            max_batch_size: 2,
+            max_batch_tokens: Some(2),
+            ..Default::default()
+        };
+        // "abcd" is 1 token and the 12 bytes are 3 tokens, i.e. over the limit on their own
+        let twelve = "abcdabcdabcd";
+        assert_eq!(
+            sub_batches(&config, inputs(&["abcd", "abcd", "abcd", twelve, "abcd"])),
+            vec![
+                inputs(&["abcd", "abcd"]),
+                inputs(&["abcd"]),
+                inputs(&[twelve]),
+                inputs(&["abcd"])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_in_sub_batches() {
+        let config = EmbeddingsSplittingConfig {
+            enabled: true,
+            max_batch_size: 2,
+            max_concurrency: 2,
+            ..Default::default()
+        };
+        // Each sub-batch gets its embeddings in reverse order, indexed within the sub-batch
+        let sent = Mutex::new(Vec::new());
+        let send = |inputs: Vec<String>| {
+            sent.lock().unwrap().push(inputs.len());
+            async move {
+                let data = inputs
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, input)| json!({"index": index, "embedding": [input]}))
+                    .collect::<Vec<_>>();
+                Ok(Json(json!({
+                    "object": "list",
+                    "data": data,
+                    "model": "bge",
+                    "usage": {"prompt_tokens": inputs.len(), "total_tokens": inputs.len()}
+                }))
+                .into_response())
+            }
+        };
+
+        let res = send_in_sub_batches(&config, inputs(&["a", "b", "c", "d", "e"]), send)
+            .await
+            .unwrap();
+        assert_eq!(*sent.lock().unwrap(), vec![2, 2, 1]);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        for (index, input) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            assert_eq!(
+                body["data"][index],
+                json!({"object": "embedding", "index": index, "embedding": [input]})
+            );
+        }
+        assert_eq!(
+            body["usage"],
+            json!({"prompt_tokens": 5, "total_tokens": 5})
+        );
+
+        // The error responses to any of the sub-batches are returned as is
+        let send = |inputs: Vec<String>| async move {
+            Ok(match inputs.len() {
+                1 => (StatusCode::TOO_MANY_REQUESTS, "slow down").into_response(),
+                _ => Json(json!({"data": [], "usage": {}})).into_response(),
+            })
+        };
+        let res = send_in_sub_batches(&config, inputs(&["a", "b", "c"]), send)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The responses can't be merged if only some sub-batches failed over to the fallback
+        let send = |inputs: Vec<String>| async move {
+            let data = (0..inputs.len())
+                .map(|index| json!({"index": index, "embedding": [0.5]}))
+                .collect::<Vec<_>>();
+            let mut res = Json(json!({"data": data})).into_response();
+            if inputs.len() == 1 {
+                res.extensions_mut().insert(Fallback);
+            }
+            Ok(res)
+        };
+        let error = send_in_sub_batches(&config, inputs(&["a", "b", "c"]), send)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            AzureError::Upstream(StatusCode::BAD_GATEWAY, _)
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    config::LoadBalancingStrategy,
+    proxy::{HttpClient, ProxyState},
+    utils::{append_path_to_uri, build_upstream_uri},
+};
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{Method, Uri},
+};
+use std::{collections::BTreeSet, time::Duration};
+use tokio::task::JoinSet;
+
+/// The metrics of the running requests as exposed by vLLM, SGLang and TGI, respectively
+const RUNNING_METRICS: &[&str] = &[
+    "vllm:num_requests_running",
+    "sglang:num_running_reqs",
+    "tgi_batch_current_size",
+];
+
+/// The metrics of the requests waiting to be scheduled as exposed by vLLM, SGLang and TGI,
+/// respectively
+const WAITING_METRICS: &[&str] = &[
+    "vllm:num_requests_waiting",
+    "sglang:num_queue_reqs",
+    "tgi_queue_size",
+];
+
+/// The metrics of the KV cache usage (between 0 and 1) as exposed by vLLM (both the former and
+/// the current name) and SGLang, as TGI does not expose it
+const KV_CACHE_USAGE_METRICS: &[&str] = &[
+    "vllm:gpu_cache_usage_perc",
+    "vllm:kv_cache_usage_perc",
+    "sglang:token_usage",
+];
+
+/// Maximum size of the metrics of an upstream, to not buffer an unbounded response
+const MAX_METRICS_BYTES: usize = 4 * 1024 * 1024;
+
+/// The load of an inference engine, as reported by its metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EngineLoad {
+    /// The number of requests being processed
+    pub running: f64,
+
+    /// The number of requests waiting to be scheduled
+    pub waiting: f64,
+
+    /// The usage of the KV cache between 0 and 1, if reported by the engine
+    pub kv_cache_usage: Option<f64>,
+}
+
+/// Parses the load out of the Prometheus metrics of the inference engine, summing the series of
+/// each metric (e.g. one per model), or returns `None` if the engine does not expose any of the
+/// known metrics
+pub fn parse(text: &str) -> Option<EngineLoad> {
+    let mut load = EngineLoad::default();
+    let mut found = false;
+
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+        let name = &line[..name_end];
+        // The labels may contain spaces, so the value is looked for after those
+        let rest = if line[name_end..].starts_with('{') {
+            line.rfind('}').map_or("", |end| &line[end + 1..])
+        } else {
+            &line[name_end..]
+        };
+        let Some(Ok(value)) = rest.split_whitespace().next().map(str::parse::<f64>) else {
+            continue;
+        };
+        if !value.is_finite() {
+            continue;
+        }
+
+        if RUNNING_METRICS.contains(&name) {
+            load.running += value;
+            found = true;
+        } else if WAITING_METRICS.contains(&name) {
+            load.waiting += value;
+            found = true;
+        } else if KV_CACHE_USAGE_METRICS.contains(&name) {
+            load.kv_cache_usage = Some(load.kv_cache_usage.unwrap_or_default().max(value));
+        }
+    }
+
+    found.then_some(load)
+}
+
+/// Scrapes the metrics of the upstreams within the pools balanced with the `least-loaded`
+/// strategy periodically, re-reading the configuration before every round, and records the
+/// load of each of those on the load balancer. The upstreams that fail to be scraped are left
+/// with their last load, which becomes stale after a while.
+pub async fn run_metrics_scrapes(state: ProxyState) {
+    loop {
+        let config = state.config.load_full();
+        let pools = config
+            .pools
+            .values()
+            .filter(|pool| pool.strategy == LoadBalancingStrategy::LeastLoaded)
+            .collect::<Vec<_>>();
+
+        let mut scrapes = JoinSet::new();
+        let mut scraped = BTreeSet::new();
+        for pool in &pools {
+            for name in &pool.upstreams {
+                let Some(upstream) = config.upstreams.get(name) else {
+                    continue;
+                };
+                let Ok(uri) = build_upstream_uri(&upstream.host, upstream.port) else {
+                    continue;
+                };
+                // The upstreams within multiple pools are only scraped once
+                if !scraped.insert(name) {
+                    continue;
+                }
+                let client = state.client.clone();
+                let name = name.clone();
+                let uri = append_path_to_uri(uri, &pool.metrics_path);
+                let timeout = Duration::from_secs(pool.scrape_interval_secs);
+                scrapes.spawn(async move { (name, scrape_upstream(client, uri, timeout).await) });
+            }
+        }
+
+        while let Some(res) = scrapes.join_next().await {
+            let Ok((name, result)) = res else {
+                continue;
+            };
+            match result {
+                Ok(load) => state.load_balancer.record_load(&name, load),
+                Err(e) => tracing::debug!("Failed to scrape the metrics of upstream '{name}': {e}"),
+            }
+        }
+        state
+            .load_balancer
+            .retain_loads(&scraped.into_iter().collect::<Vec<_>>());
+
+        let interval = pools
+            .iter()
+            .map(|pool| pool.scrape_interval_secs)
+            .min()
+            .unwrap_or(5);
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Sends a GET request to the metrics endpoint of the upstream, and parses the load out of the
+/// metrics within the timeout
+async fn scrape_upstream(
+    client: HttpClient,
+    uri: Uri,
+    timeout: Duration,
+) -> Result<EngineLoad, String> {
+    let req: Request<Body> = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let scrape = async {
+        let res = client.request(req).await.map_err(|e| e.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("unexpected status {}", res.status()));
+        }
+        let body = to_bytes(Body::new(res.into_body()), MAX_METRICS_BYTES)
+            .await
+            .map_err(|e| e.to_string())?;
+        parse(&String::from_utf8_lossy(&body))
+            .ok_or_else(|| "no running nor waiting requests metrics found".to_string())
+    };
+
+    tokio::time::timeout(timeout, scrape)
+        .await
+        .map_err(|_| format!("timed out after {timeout:?}"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connector::UpstreamConnector, utils::serve_locally};
+    use axum::{http::StatusCode, routing::get, Router};
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+
+    #[tokio::test]
+    async fn test_scrape_upstream() {
+        let uri = serve_locally(
+            Router::new()
+                .route(
+                    "/metrics",
+                    get(|| async { "sglang:num_running_reqs 3\nsglang:num_queue_reqs 1\n" }),
+                )
+                .route(
+                    "/unknown",
+                    get(|| async { "process_cpu_seconds_total 1.5\n" }),
+                )
+                .route(
+                    "/failing",
+                    get(|| async { StatusCode::SERVICE_UNAVAILABLE }),
+                )
+                .route(
+                    "/slow",
+                    get(|| async {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        "sglang:num_running_reqs 3\n"
+                    }),
+                ),
+        )
+        .await;
+        let client: HttpClient =
+            Client::builder(TokioExecutor::new()).build(UpstreamConnector::new());
+        let scrape = |path: &str| {
+            let uri = append_path_to_uri(uri.clone(), path);
+            scrape_upstream(client.clone(), uri, Duration::from_millis(200))
+        };
+
+        assert_eq!(
+            scrape("/metrics").await,
+            Ok(EngineLoad {
+                running: 3.0,
+                waiting: 1.0,
+                kv_cache_usage: None,
+            })
+        );
+        assert_eq!(
+            scrape("/unknown").await,
+            Err("no running nor waiting requests metrics found".to_string())
+        );
+        assert_eq!(
+            scrape("/failing").await,
+            Err("unexpected status 503 Service Unavailable".to_string())
+        );
+        assert_eq!(
+            scrape("/slow").await,
+            Err("timed out after 200ms".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let vllm = r#"
+# HELP vllm:num_requests_running Number of requests currently running on GPU.
+# TYPE vllm:num_requests_running gauge
+vllm:num_requests_running{engine="0",model_name="microsoft/Phi-4"} 12.0
+vllm:num_requests_running{engine="1",model_name="microsoft/Phi-4"} 4.0
+vllm:num_requests_waiting{engine="0",model_name="microsoft/Phi-4"} 3.0
+vllm:kv_cache_usage_perc{engine="0",model_name="microsoft/Phi-4"} 0.75
+vllm:num_requests_swapped{engine="0",model_name="microsoft/Phi-4"} 1.0
+"#;
+        assert_eq!(
+            parse(vllm),
+            Some(EngineLoad {
+                running: 16.0,
+                waiting: 3.0,
+                kv_cache_usage: Some(0.75),
+            })
+        );
+
+        let tgi = "tgi_batch_current_size 2\ntgi_queue_size{label=\"with spaces\"} 5 1700000000\n";
+        assert_eq!(
+            parse(tgi),
+            Some(EngineLoad {
+                running: 2.0,
+                waiting: 5.0,
+                kv_cache_usage: None,
+            })
+        );
+
+        assert_eq!(parse("process_cpu_seconds_total 1.5\n"), None);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::middlewares::request_id::REQUEST_ID;
 use axum::{
-    http::StatusCode,
+    body::Bytes,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Json, Response},
 };
 use serde_json::json;
@@ -8,7 +10,7 @@ use thiserror::Error;
 /// Azure AI Model Inference API and proxy errors
 ///
 /// Reference: https://github.com/microsoft/api-guidelines/blob/vNext/azure/Guidelines.md#handling-errors
-#[derive(Debug, Error)]
+#[derive(Debug, Clone, Error)]
 pub enum AzureError {
     #[error("The api-version query parameter (?api-version=) is required for all requests.")]
     MissingApiVersionParameter,
@@ -21,10 +23,40 @@ pub enum AzureError {
 
     #[error("Upstream error: '{0}' (status {1}).")]
     Upstream(StatusCode, String),
+
+    #[error("Access denied due to missing or invalid API key.")]
+    Unauthorized,
+
+    #[error("No upstream is configured for the route '{0}'.")]
+    NoUpstream(String),
+
+    #[error("{0}")]
+    InvalidConfiguration(String),
+
+    #[error("{0}")]
+    ServiceUnavailable(String, u64),
+
+    #[error("{0}")]
+    TooManyRequests(String, u64),
+
+    #[error("{0}")]
+    GatewayTimeout(String),
+
+    #[error("{0}")]
+    RequestTimeout(String),
 }
 
-impl IntoResponse for AzureError {
-    fn into_response(self) -> Response {
+impl AzureError {
+    /// Returns the status, the JSON body and the `Retry-After` seconds (if any) of the error
+    fn into_parts(self) -> (StatusCode, serde_json::Value, Option<u64>) {
+        // Lets the client know when to retry, if the error is expected to be temporary
+        let retry_after = match &self {
+            Self::ServiceUnavailable(_, retry_after) | Self::TooManyRequests(_, retry_after) => {
+                Some(*retry_after)
+            }
+            _ => None,
+        };
+
         let (status, code, message) = match self {
             Self::MissingApiVersionParameter => (
                 StatusCode::BAD_REQUEST,
@@ -45,15 +77,74 @@ impl IntoResponse for AzureError {
                 message,
             ),
             Self::Upstream(status, message) => (status, "UpstreamApi", message),
+            Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized", self.to_string()),
+            Self::NoUpstream(_) => (StatusCode::NOT_FOUND, "NoUpstream", self.to_string()),
+            // The configuration is the one of the proxy, so it's not an error of the client
+            Self::InvalidConfiguration(message) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "InvalidConfiguration",
+                message,
+            ),
+            Self::ServiceUnavailable(message, _) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                message,
+            ),
+            Self::TooManyRequests(message, _) => {
+                (StatusCode::TOO_MANY_REQUESTS, "TooManyRequests", message)
+            }
+            Self::GatewayTimeout(message) => {
+                (StatusCode::GATEWAY_TIMEOUT, "GatewayTimeout", message)
+            }
+            Self::RequestTimeout(message) => (StatusCode::REQUEST_TIMEOUT, "Timeout", message),
         };
 
-        let body = Json(json!({
-            "error": {
-                "code": code,
-                "message": message
-            }
-        }));
+        let mut error = json!({
+            "code": code,
+            "message": message
+        });
+        // Includes the ID of the request being served (if any), so that the error can be
+        // correlated with the proxy and the upstream logs
+        if let Ok(request_id) = REQUEST_ID.try_with(|request_id| request_id.clone()) {
+            error["request_id"] = request_id.into();
+        }
+
+        (status, json!({ "error": error }), retry_after)
+    }
+
+    /// Returns the error as a terminal Server-Sent Event, for the errors once a stream started
+    pub fn into_event(self) -> Bytes {
+        let (_, body, _) = self.into_parts();
+        Bytes::from(format!("event: error\ndata: {body}\n\n"))
+    }
+}
+
+impl IntoResponse for AzureError {
+    fn into_response(self) -> Response {
+        let (status, body, retry_after) = self.into_parts();
+        let body = Json(body);
 
-        (status, body).into_response()
+        match retry_after {
+            Some(retry_after) => {
+                (status, [(RETRY_AFTER, retry_after.to_string())], body).into_response()
+            }
+            None => (status, body).into_response(),
+        }
     }
 }
+
+/// Proxy configuration errors, reported on startup before the proxy starts listening
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read the configuration file '{0}': {1}")]
+    Read(String, String),
+
+    #[error("Failed to parse the configuration file '{0}': {1}")]
+    Parse(String, String),
+
+    #[error("Invalid configuration:\n{}", .0.iter().map(|e| format!("  - {e}")).collect::<Vec<_>>().join("\n"))]
+    Invalid(Vec<String>),
+
+    #[error("Failed to serialize the configuration: {0}")]
+    Serialize(String),
+}
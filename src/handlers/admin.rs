@@ -0,0 +1,12 @@
+use crate::{errors::AzureError, proxy::ProxyState};
+use axum::{extract::State, http::StatusCode};
+
+/// This function reloads the configuration, same as when the SIGHUP signal is received, swapping
+/// the routing table, API keys and limits only if the new configuration is valid, and otherwise
+/// rejecting the reload with the validation errors.
+pub async fn reload_handler(State(state): State<ProxyState>) -> Result<StatusCode, AzureError> {
+    state
+        .reload()
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(|e| AzureError::InvalidConfiguration(e.to_string()))
+}
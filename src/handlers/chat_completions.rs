@@ -1,22 +1,27 @@
 use crate::{
+    cache::{CacheControl, ResponseCache},
+    config::ResolvedUpstream,
     errors::AzureError,
     proxy::ProxyState,
     schemas::{
         azure::{ExtraParameters, QueryParameters},
         chat_completions::ChatRequest,
     },
+    upstream::{insert_variant, send_with_fallback, Fallback},
     utils::{append_path_to_uri, check_api_version},
+    UpstreamType,
 };
 use axum::{
-    body::Body,
+    body::Bytes,
     extract::{Json, Query, Request, State},
     http::{
         header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
-        HeaderMap, Method, StatusCode,
+        HeaderMap, Method,
     },
     response::IntoResponse,
 };
 use std::collections::HashMap;
+use tracing::Span;
 
 /// This function proxies the requests to `/chat/completions` to the underlying `/v1/chat/completions`,
 /// making sure that the I/O schemas are compliant with the Azure AI Model Inference API
@@ -29,6 +34,11 @@ pub async fn chat_completions_handler(
     State(state): State<ProxyState>,
     Json(mut payload): Json<ChatRequest>,
 ) -> Result<impl IntoResponse, AzureError> {
+    Span::current()
+        .record("az.api_version", query.api_version.as_deref())
+        .record("gen_ai.request.model", payload.model.as_deref());
+    let validate = tracing::info_span!("validate").entered();
+
     // Checks that the `api-version` query parameter is provided and valid
     check_api_version(query.api_version)?;
 
@@ -74,25 +84,213 @@ pub async fn chat_completions_handler(
         ExtraParameters::PassThrough => (),
     };
 
-    // Updates the request URI whilst keeping the headers, parameters, etc.
-    let uri = append_path_to_uri(state.uri, "/v1/chat/completions");
+    // Resolves the upstream serving the requested model (if configured) or the route otherwise,
+    // picking one out of the pool if that's a pool of upstreams; the pools balanced on the prefix
+    // of the conversation route the requests sharing it to the same upstream
+    let config = state.config.load_full();
+    let upstream = state
+        .resolve(
+            &config,
+            &UpstreamType::ChatCompletions,
+            payload.model.as_deref(),
+            &headers,
+            Some(&|chars| payload.prefix(chars)),
+        )
+        .ok_or_else(|| {
+            AzureError::NoUpstream(UpstreamType::ChatCompletions.as_str().to_string())
+        })?;
+
+    validate.exit();
+
+    // Serves the deterministic requests from the cache if enabled, unless the client bypasses it
+    // via the `Cache-Control` header, and caches the response from the upstream otherwise (unless
+    // served by the fallback, as another model may have generated it); the key is computed before
+    // the model is rewritten, so that the requests are cached as received
+    let route = UpstreamType::ChatCompletions.as_str();
+    let cache_control = CacheControl::from(&headers);
+    let cache_key = (config.cache.enabled && payload.is_deterministic()).then(|| {
+        ResponseCache::key(
+            &UpstreamType::ChatCompletions,
+            &upstream,
+            &headers,
+            &payload,
+        )
+    });
+    if let Some(key) = &cache_key {
+        if !cache_control.lookup {
+            state.metrics.record_cache_request(route, "bypass");
+        } else if let Some(mut res) = state.cache.get(&config.cache, key).await {
+            state.metrics.record_cache_request(route, "hit");
+            insert_variant(&mut res, &upstream);
+            return Ok(res);
+        } else {
+            state.metrics.record_cache_request(route, "miss");
+        }
+    }
+
+    // Builds the request for either the upstream or its fallback, rewriting the model name if the
+    // requested one is an alias of the model in the upstream (or the fallback serves another one)
+    let requested_model = payload.model.clone();
+    let build = |upstream: &ResolvedUpstream| {
+        payload.model = upstream.model.clone().or_else(|| requested_model.clone());
+
+        // Updates the request URI whilst keeping the headers, parameters, etc.
+        let uri = append_path_to_uri(upstream.uri.clone(), "/v1/chat/completions");
+
+        // The payload contains the user inputs, so it's only logged if explicitly enabled
+        if config.logging.log_payloads {
+            tracing::debug!("Request payload: {:?}", payload);
+        }
 
-    // Forwards request to the underlying upstream API
-    tracing::info!("Proxying {} request to {} with {:?}", method, uri, payload);
+        // Build request again preserving the method, body and headers, with the body buffered so
+        // that it can be replayed on retries
+        let mut req: Request<Bytes> = Request::builder()
+            .method(method.clone())
+            .uri(uri)
+            .body((&payload).into())
+            .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
 
-    // Build request again preserving the method, body and headers
-    let mut req: Request<Body> = Request::builder()
-        .method(method)
-        .uri(uri)
-        .body(payload.into())
-        .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
+        *req.headers_mut() = headers.clone();
+        Ok(req)
+    };
 
-    *req.headers_mut() = headers;
+    let res = send_with_fallback(&state, &upstream, &headers, build).await?;
+    Ok(match cache_key {
+        Some(key) if cache_control.store && res.extensions().get::<Fallback>().is_none() => {
+            state.cache.store(&config.cache, key, res)
+        }
+        _ => res,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::CACHE, traffic_split::VARIANT, utils::serve_locally};
+    use axum::{
+        body::{to_bytes, Body},
+        http::StatusCode,
+        routing::post,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+    use tower_service::Service;
 
-    state
-        .client
-        .request(req)
-        .await
-        .map_err(|e| AzureError::Upstream(StatusCode::BAD_GATEWAY, e.to_string()))
-        .map(|res| res.into_response())
+    #[tokio::test]
+    async fn test_cache() {
+        // Both upstreams number the responses they generate, so that the cached ones are told apart
+        let generated = Arc::new(AtomicU64::new(0));
+        let upstream = async |status: StatusCode| {
+            let generated = generated.clone();
+            let handler = async move || {
+                let id = generated.fetch_add(1, Ordering::Relaxed) + 1;
+                (status, axum::Json(json!({"id": id})))
+            };
+            let uri = serve_locally(Router::new().route("/v1/chat/completions", post(handler)));
+            uri.await.port_u16().unwrap()
+        };
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.vllm]
+            host = "127.0.0.1"
+            port = {}
+
+            [upstreams.failing]
+            host = "127.0.0.1"
+            port = {}
+
+            [routes.chat-completions]
+            upstream = "vllm"
+
+            [models.phi-failing]
+            upstream = "failing"
+            fallback = {{ upstream = "vllm" }}
+
+            [splits.phi-ab]
+            variants.base = {{ upstream = "vllm", weight = 100 }}
+
+            [models.phi-ab]
+            upstream = "phi-ab"
+
+            [cache]
+            enabled = true
+            "#,
+            upstream(StatusCode::OK).await,
+            upstream(StatusCode::INTERNAL_SERVER_ERROR).await,
+        ));
+        let mut app = Router::new()
+            .route("/chat/completions", post(chat_completions_handler))
+            .with_state(state.clone());
+        // Returns the `x-cache` header and the number of the response
+        let mut send = async |model: &str, headers: &[(&str, &str)]| {
+            let mut request = Request::post("/chat/completions?api-version=2024-05-01-preview")
+                .header("content-type", "application/json");
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            let body = json!({
+                "model": model,
+                "messages": [{"role": "user", "content": "What is Deep Learning?"}],
+                "temperature": 0
+            });
+            let request = request.body(Body::from(body.to_string())).unwrap();
+            let res = app.call(request).await.unwrap();
+            let cache = res
+                .headers()
+                .get(CACHE)
+                .map(|value| value.to_str().unwrap().to_string());
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let body = serde_json::from_slice::<Value>(&body).unwrap();
+            (cache, body["id"].as_u64().unwrap())
+        };
+        let (hit, miss) = (Some("hit".to_string()), Some("miss".to_string()));
+
+        assert_eq!(send("phi", &[]).await, (miss.clone(), 1));
+        assert_eq!(send("phi", &[]).await, (hit.clone(), 1));
+        // A fresh response is generated when bypassed, and it replaces the cached one
+        assert_eq!(
+            send("phi", &[("cache-control", "no-cache")]).await,
+            (miss.clone(), 2)
+        );
+        assert_eq!(send("phi", &[]).await, (hit.clone(), 2));
+        // The responses are not shared across the callers with different credentials
+        assert_eq!(
+            send("phi", &[("api-key", "another-key")]).await,
+            (miss.clone(), 3)
+        );
+
+        // Nor the ones generated by the fallback, as those may come from another model
+        assert_eq!(send("phi-failing", &[]).await, (None, 5));
+        assert_eq!(send("phi-failing", &[]).await, (None, 7));
+
+        // The responses served from the cache are attributed to the variant too
+        for cache in ["miss", "hit"] {
+            let body = json!({
+                "model": "phi-ab",
+                "messages": [{"role": "user", "content": "What is Deep Learning?"}],
+                "temperature": 0
+            });
+            let request = Request::post("/chat/completions?api-version=2024-05-01-preview")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let res = app.call(request).await.unwrap();
+            assert_eq!(res.headers()[CACHE], cache);
+            assert_eq!(res.headers()[VARIANT], "base");
+            to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        }
+
+        let encoded = state.metrics.encode();
+        for line in [
+            r#"azure_openai_proxy_cache_requests_total{result="hit",route="chat-completions"} 3"#,
+            r#"azure_openai_proxy_cache_requests_total{result="miss",route="chat-completions"} 5"#,
+            r#"azure_openai_proxy_cache_requests_total{result="bypass",route="chat-completions"} 1"#,
+        ] {
+            assert!(encoded.contains(line), "missing {line}");
+        }
+    }
 }
@@ -1,22 +1,29 @@
 use crate::{
+    cache::CacheControl,
+    config::ResolvedUpstream,
+    embeddings_splitter::send_in_sub_batches,
     errors::AzureError,
+    middlewares::auth::credentials_hash,
     proxy::ProxyState,
     schemas::{
         azure::{ExtraParameters, QueryParameters},
         embeddings::EmbeddingsRequest,
     },
+    upstream::{insert_variant, send_with_fallback},
     utils::{append_path_to_uri, check_api_version},
+    UpstreamType,
 };
 use axum::{
-    body::Body,
+    body::Bytes,
     extract::{Json, Query, Request, State},
     http::{
         header::{CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING},
-        HeaderMap, Method, StatusCode,
+        HeaderMap, Method,
     },
     response::IntoResponse,
 };
 use std::collections::HashMap;
+use tracing::Span;
 
 /// This function proxies the requests to `/embeddings` to the underlying `/v1/embeddings`,
 /// making sure that the I/O schemas are compliant with the Azure AI Model Inference API
@@ -29,6 +36,11 @@ pub async fn embeddings_handler(
     State(state): State<ProxyState>,
     Json(mut payload): Json<EmbeddingsRequest>,
 ) -> Result<impl IntoResponse, AzureError> {
+    Span::current()
+        .record("az.api_version", query.api_version.as_deref())
+        .record("gen_ai.request.model", payload.model.as_str());
+    let validate = tracing::info_span!("validate").entered();
+
     // Checks that the `api-version` query parameter is provided and valid
     check_api_version(query.api_version)?;
 
@@ -74,25 +86,223 @@ pub async fn embeddings_handler(
         ExtraParameters::PassThrough => (),
     };
 
-    // Updates the request URI whilst keeping the headers, parameters, etc.
-    let uri = append_path_to_uri(state.uri, "/v1/embeddings");
+    // Resolves the upstream serving the requested model (if configured) or the route otherwise,
+    // picking one out of the pool if that's a pool of upstreams
+    let config = state.config.load_full();
+    let upstream = state
+        .resolve(
+            &config,
+            &UpstreamType::Embeddings,
+            Some(payload.model.as_str()),
+            &headers,
+            None,
+        )
+        .ok_or_else(|| AzureError::NoUpstream(UpstreamType::Embeddings.as_str().to_string()))?;
+
+    validate.exit();
+
+    // Looks up each input in the cache if enabled, unless the client bypasses it via the
+    // `Cache-Control` header, sending only the inputs not cached yet to the upstream; the inputs
+    // are looked up before the model is rewritten, so that those are cached as received
+    let route = UpstreamType::Embeddings.as_str();
+    let cache_control = CacheControl::from(&headers);
+    let cached = (config.embeddings_cache.enabled && cache_control.store).then(|| {
+        state.embeddings_cache.lookup(
+            &config.embeddings_cache,
+            &payload,
+            &upstream,
+            &headers,
+            cache_control.lookup,
+        )
+    });
+    if config.embeddings_cache.enabled && !cache_control.lookup {
+        state.metrics.record_cache_request(route, "bypass");
+    }
+    if let Some(cached) = &cached {
+        if cache_control.lookup {
+            state.metrics.record_cache_request(route, cached.result());
+            if let Some(mut res) = cached.response() {
+                insert_variant(&mut res, &upstream);
+                return Ok(res);
+            }
+        }
+        if let Some(misses) = cached.misses() {
+            payload = payload.with_inputs(misses.to_vec());
+        }
+    }
+
+    // Batches the concurrent requests with the same parameters, variant of the traffic split (if
+    // any) and credentials if enabled, so that those are sent to the upstream as a single request
+    // on behalf of the same caller
+    let inputs = payload
+        .inputs()
+        .into_iter()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let batch_key = config.embeddings_batching.enabled.then(|| {
+        let variant = upstream
+            .variant
+            .as_ref()
+            .map(|(split, variant)| format!("{split}/{variant}"));
+        format!(
+            "{}\n{}\n{:016x}",
+            payload.parameters(),
+            variant.unwrap_or_default(),
+            credentials_hash(&headers)
+        )
+    });
+
+    // Sends the request with the given inputs (i.e. those of the batch or sub-batch, if any) to
+    // either the upstream or its fallback, rewriting the model name if the requested one is an
+    // alias of the model in the upstream (or the fallback serves another one)
+    let send = {
+        let (state, config) = (state.clone(), config.clone());
+        move |inputs: Vec<String>| {
+            // The request is sent as is unless its inputs are replaced, e.g. keeping a single
+            // input as such
+            let mut payload = match payload.inputs() == inputs {
+                true => payload.clone(),
+                false => payload.with_inputs(inputs),
+            };
+            let (state, config, headers) = (state.clone(), config.clone(), headers.clone());
+            let (method, upstream) = (method.clone(), upstream.clone());
+            async move {
+                let requested_model = payload.model.clone();
+                let build = |upstream: &ResolvedUpstream| {
+                    payload.model = upstream
+                        .model
+                        .clone()
+                        .unwrap_or_else(|| requested_model.clone());
+
+                    // Updates the request URI whilst keeping the headers, parameters, etc.
+                    let uri = append_path_to_uri(upstream.uri.clone(), "/v1/embeddings");
+
+                    // The payload contains the user inputs, so it's only logged if explicitly
+                    // enabled
+                    if config.logging.log_payloads {
+                        tracing::debug!("Request payload: {:?}", payload);
+                    }
+
+                    // Build request again preserving the method, body and headers, with the body
+                    // buffered so that it can be replayed on retries
+                    let mut req: Request<Bytes> = Request::builder()
+                        .method(method.clone())
+                        .uri(uri)
+                        .body((&payload).into())
+                        .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
+
+                    *req.headers_mut() = headers.clone();
+                    Ok(req)
+                };
+
+                send_with_fallback(&state, &upstream, &headers, build).await
+            }
+        }
+    };
+
+    // Splits the inputs exceeding the limits of the upstream into sub-batches if enabled, sent
+    // concurrently and merged back into a single response
+    let send = {
+        let config = config.clone();
+        move |inputs: Vec<String>| async move {
+            send_in_sub_batches(&config.embeddings_splitting, inputs, send).await
+        }
+    };
+
+    let res = match batch_key {
+        Some(key) => {
+            state
+                .embeddings_batcher
+                .submit(
+                    &config.embeddings_batching,
+                    &state.metrics,
+                    key,
+                    inputs,
+                    send,
+                )
+                .await?
+        }
+        None => send(inputs).await?,
+    };
+    match cached {
+        Some(cached) => cached.merge(res).await,
+        None => Ok(res),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::serve_locally;
+    use axum::{
+        body::{to_bytes, Body},
+        routing::post,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn test_batches_per_credentials() {
+        // The upstream counts the requests it receives, i.e. the batches
+        let sent = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let sent = sent.clone();
+            async move |axum::Json(request): axum::Json<Value>| {
+                sent.fetch_add(1, Ordering::Relaxed);
+                let data = (0..request["input"].as_array().map_or(1, Vec::len))
+                    .map(|index| json!({"index": index, "embedding": [0.5]}))
+                    .collect::<Vec<_>>();
+                axum::Json(json!({"data": data}))
+            }
+        };
+        let uri = serve_locally(Router::new().route("/v1/embeddings", post(handler))).await;
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.tei]
+            host = "127.0.0.1"
+            port = {}
 
-    // Forwards request to the underlying upstream API
-    tracing::info!("Proxying {} request to {} with {:?}", method, uri, payload);
+            [routes.embeddings]
+            upstream = "tei"
 
-    // Build request again preserving the method, body and headers
-    let mut req: Request<Body> = Request::builder()
-        .method(method)
-        .uri(uri)
-        .body(payload.into())
-        .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
+            [embeddings_batching]
+            enabled = true
+            window_ms = 50
+            "#,
+            uri.port_u16().unwrap(),
+        ));
+        let app = Router::new()
+            .route("/embeddings", post(embeddings_handler))
+            .with_state(state);
+        let send = async |api_key: &str, input: &str| {
+            let request = Request::post("/embeddings?api-version=2024-05-01-preview")
+                .header("content-type", "application/json")
+                .header("api-key", api_key)
+                .body(Body::from(
+                    json!({"model": "bge", "input": input}).to_string(),
+                ))
+                .unwrap();
+            let res = app.clone().call(request).await.unwrap();
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<Value>(&body).unwrap()["data"]
+                .as_array()
+                .unwrap()
+                .len()
+        };
 
-    *req.headers_mut() = headers;
+        // The concurrent requests with the same credentials share a batch
+        let (first, second) = tokio::join!(send("sk-first", "a"), send("sk-first", "b"));
+        assert_eq!((first, second), (1, 1));
+        assert_eq!(sent.swap(0, Ordering::Relaxed), 1);
 
-    state
-        .client
-        .request(req)
-        .await
-        .map_err(|e| AzureError::Upstream(StatusCode::BAD_GATEWAY, e.to_string()))
-        .map(|res| res.into_response())
+        // But the ones with different credentials are not sent on behalf of one another
+        let (first, second) = tokio::join!(send("sk-first", "a"), send("sk-second", "b"));
+        assert_eq!((first, second), (1, 1));
+        assert_eq!(sent.load(Ordering::Relaxed), 2);
+    }
 }
@@ -1,3 +1,119 @@
-pub async fn health_handler() -> &'static str {
+use crate::{
+    health_check::{unservable_upstream, HealthStatus},
+    proxy::ProxyState,
+    schemas::health::HealthResponse,
+};
+use axum::{extract::State, http::StatusCode, response::Json};
+
+/// This function reports the aggregated health of the upstreams, as observed by the periodic
+/// health checks, responding with a 503 status if any of the routed upstreams is unhealthy (or
+/// every upstream of a pool), so that the orchestrator (e.g. Azure ML or Kubernetes) can act upon
+/// it. The state of the circuit breakers is reported too, but it's not considered for the
+/// aggregated health, as an open circuit already rejects the requests to the failing upstream.
+pub async fn health_handler(State(state): State<ProxyState>) -> (StatusCode, Json<HealthResponse>) {
+    let status = if routes_servable(&state) {
+        HealthStatus::Healthy
+    } else {
+        HealthStatus::Unhealthy
+    };
+
+    let status_code = match status {
+        HealthStatus::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+
+    (
+        status_code,
+        Json(HealthResponse {
+            status,
+            upstreams: state.health.snapshot(),
+            circuits: state.circuit_breakers.snapshot(),
+        }),
+    )
+}
+
+/// This function reports that the proxy is alive, regardless of the upstreams, to be used as the
+/// liveness probe (e.g. the `liveness_route` of an Azure ML managed online endpoint) so that the
+/// container is not restarted while the upstream is still starting.
+pub async fn liveness_handler() -> &'static str {
     "OK"
 }
+
+/// This function reports whether the proxy is ready to serve requests, meaning that all the
+/// upstreams have answered `/v1/models` at least once and none of those is currently unhealthy (or
+/// at least one of the upstreams of each pool), to be used as the readiness probe (e.g. the
+/// `readiness_route` of an Azure ML managed online endpoint) so that the traffic is only routed to
+/// the proxy once it can be served.
+pub async fn readiness_handler(State(state): State<ProxyState>) -> (StatusCode, &'static str) {
+    let ready = state.startup.is_open() && routes_servable(&state);
+
+    if ready {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "Not Ready")
+    }
+}
+
+/// Returns whether every upstream serving a route or a model can serve requests, i.e. the ones
+/// not unhealthy
+fn routes_servable(state: &ProxyState) -> bool {
+    let config = state.config.load_full();
+    unservable_upstream(&config, |name| {
+        state.health.status(name) != HealthStatus::Unhealthy
+    })
+    .is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_health_of_pools() {
+        let state = ProxyState::from_toml(
+            r#"
+            [upstreams.vllm]
+            port = 8080
+
+            [upstreams.vllm-1]
+            port = 8081
+
+            [upstreams.unused]
+            port = 8082
+
+            [pools.vllm-replicas]
+            upstreams = ["vllm", "vllm-1"]
+
+            [routes.chat-completions]
+            upstream = "vllm-replicas"
+            "#,
+        );
+        state.startup.open();
+        let unhealthy = |name| {
+            state
+                .health
+                .record(name, Err("connection refused".to_string()), 1, 1)
+        };
+        let status = async || {
+            let (health, _) = health_handler(State(state.clone())).await;
+            let (readiness, _) = readiness_handler(State(state.clone())).await;
+            (health, readiness)
+        };
+
+        // Neither a single replica of the pool nor an upstream that no route uses is enough
+        unhealthy("vllm");
+        unhealthy("unused");
+        assert_eq!(status().await, (StatusCode::OK, StatusCode::OK));
+
+        unhealthy("vllm-1");
+        let (_, Json(health)) = health_handler(State(state.clone())).await;
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert_eq!(
+            status().await,
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::SERVICE_UNAVAILABLE
+            )
+        );
+    }
+}
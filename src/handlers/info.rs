@@ -5,15 +5,17 @@ use crate::{
         azure::QueryParameters,
         info::{InfoResponse, ModelType, OpenAIInfoResponse},
     },
+    upstream::send_request,
     utils::{append_path_to_uri, check_api_version},
     UpstreamType,
 };
 use axum::{
-    body::{to_bytes, Body},
+    body::{to_bytes, Bytes},
     extract::{Query, Request, State},
-    http::{HeaderMap, Method, StatusCode},
-    response::{IntoResponse, Json},
+    http::{HeaderMap, Method},
+    response::Json,
 };
+use tracing::Span;
 
 pub async fn info_handler(
     method: Method,
@@ -21,35 +23,46 @@ pub async fn info_handler(
     Query(query): Query<QueryParameters>,
     State(state): State<ProxyState>,
 ) -> Result<Json<InfoResponse>, AzureError> {
+    Span::current().record("az.api_version", query.api_version.as_deref());
+
     // Checks that the `api-version` query parameter is provided and valid
     check_api_version(query.api_version)?;
 
+    // Resolves the upstream serving the chat completions route if configured, or the embeddings
+    // route otherwise, as the proxy is expected to serve a single model type
+    let config = state.config.load_full();
+    let upstream_type = if config.routes.contains_key(&UpstreamType::ChatCompletions) {
+        UpstreamType::ChatCompletions
+    } else {
+        UpstreamType::Embeddings
+    };
+    let upstream = state
+        .resolve(&config, &upstream_type, None, &headers, None)
+        .ok_or_else(|| AzureError::NoUpstream(upstream_type.as_str().to_string()))?;
+
     // Updates the request URI whilst keeping the headers, parameters, etc.
-    let uri = append_path_to_uri(state.uri, "/v1/models");
+    let uri = append_path_to_uri(upstream.uri.clone(), "/v1/models");
 
     // Forwards request to the underlying upstream API
     tracing::info!("Proxying {} request to {}", method, uri);
 
-    // Build request again preserving the method, body and headers
-    let mut req: Request<Body> = Request::builder()
+    // Build request again preserving the method, body and headers, with the body buffered so
+    // that it can be replayed on retries
+    let mut req: Request<Bytes> = Request::builder()
         .method(method)
         .uri(uri)
-        .body(Body::empty())
+        .body(Bytes::new())
         .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
 
     *req.headers_mut() = headers;
 
-    let body = state
-        .client
-        .request(req)
-        .await
-        .map_err(|e| AzureError::Upstream(StatusCode::BAD_GATEWAY, e.to_string()))
-        .map(|r| r.into_response())?;
+    let body = send_request(&state, &upstream, req).await?;
 
     // Parsing response body into Azure AI Model Inference compliant JSON
-    let body_bytes = to_bytes(body.into_body(), std::usize::MAX)
+    let body_bytes = to_bytes(body.into_body(), usize::MAX)
         .await
         .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
+    let _translate = tracing::info_span!("translate").entered();
 
     let info: OpenAIInfoResponse = serde_json::from_slice(&body_bytes)
         .map_err(|e| AzureError::InternalParsing(e.to_string()))?;
@@ -62,7 +75,7 @@ pub async fn info_handler(
         // returned even if "not correct"; when working with models from the Hugging Face Hub
         .unwrap_or((&info.data[0].id, &info.data[0].id));
 
-    let model_type = match &state.upstream_type {
+    let model_type = match upstream_type {
         UpstreamType::ChatCompletions => ModelType::ChatCompletion,
         UpstreamType::Embeddings => ModelType::Embeddings,
     };
@@ -0,0 +1,11 @@
+use crate::proxy::ProxyState;
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+
+/// This function exposes the metrics of the proxy in the Prometheus text format, so that those can
+/// be scraped by Prometheus (or any compatible agent e.g. Azure Monitor).
+pub async fn metrics_handler(State(state): State<ProxyState>) -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        state.metrics.encode(),
+    )
+}
@@ -0,0 +1,465 @@
+use crate::{
+    config::Config,
+    proxy::{HttpClient, ProxyState},
+    utils::{append_path_to_uri, build_upstream_uri},
+};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{Method, Uri},
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinSet;
+
+/// The health status of an upstream
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    /// The upstream has not been checked yet, or not enough checks have been run to decide
+    #[default]
+    Unknown,
+
+    /// The upstream passed the last `healthy_threshold` consecutive health checks
+    Healthy,
+
+    /// The upstream failed the last `unhealthy_threshold` consecutive health checks
+    Unhealthy,
+}
+
+/// The health of an upstream, as observed by the periodic health checks
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct UpstreamHealth {
+    /// The current health status of the upstream
+    pub status: HealthStatus,
+
+    /// The number of consecutive failed health checks
+    pub consecutive_failures: u32,
+
+    /// The error of the last failed health check, if the last health check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+
+    #[serde(skip)]
+    consecutive_successes: u32,
+
+    #[serde(skip)]
+    unhealthy_since: Option<Instant>,
+
+    #[serde(skip)]
+    healthy_since: Option<Instant>,
+
+    #[serde(skip)]
+    has_been_healthy: bool,
+}
+
+/// Registry with the health of each upstream, shared between the health checks running in the
+/// background and the proxy endpoints
+#[derive(Debug, Clone, Default)]
+pub struct HealthRegistry {
+    upstreams: Arc<RwLock<BTreeMap<String, UpstreamHealth>>>,
+}
+
+impl HealthRegistry {
+    /// Returns the current health of all the upstreams
+    pub fn snapshot(&self) -> BTreeMap<String, UpstreamHealth> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    /// Records the result of a health check for the upstream, updating its health status once the
+    /// consecutive failures or successes reach the configured thresholds
+    pub fn record(
+        &self,
+        name: &str,
+        result: Result<(), String>,
+        unhealthy_threshold: u32,
+        healthy_threshold: u32,
+    ) -> UpstreamHealth {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let health = upstreams.entry(name.to_string()).or_default();
+
+        match result {
+            Ok(()) => {
+                health.consecutive_failures = 0;
+                health.consecutive_successes += 1;
+                health.last_error = None;
+                if health.consecutive_successes >= healthy_threshold {
+                    if health.status != HealthStatus::Healthy {
+                        tracing::info!("Upstream '{name}' is healthy");
+                        // Only the upstreams that recovered are in slow start, not the new ones
+                        health.healthy_since = health.has_been_healthy.then(Instant::now);
+                    }
+                    health.status = HealthStatus::Healthy;
+                    health.unhealthy_since = None;
+                    health.has_been_healthy = true;
+                }
+            }
+            Err(e) => {
+                health.consecutive_successes = 0;
+                health.consecutive_failures += 1;
+                tracing::debug!("Health check for upstream '{name}' failed with: {e}");
+                health.last_error = Some(e);
+                if health.consecutive_failures >= unhealthy_threshold {
+                    if health.status != HealthStatus::Unhealthy {
+                        tracing::warn!(
+                            "Upstream '{name}' is unhealthy after {} consecutive failed health checks, last error: {}",
+                            health.consecutive_failures,
+                            health.last_error.as_deref().unwrap_or_default()
+                        );
+                        health.unhealthy_since = Some(Instant::now());
+                    }
+                    health.status = HealthStatus::Unhealthy;
+                }
+            }
+        }
+
+        health.clone()
+    }
+
+    /// Returns the health status of the upstream, `Unknown` if it has not been checked yet
+    pub fn status(&self, name: &str) -> HealthStatus {
+        self.upstreams
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|health| health.status)
+            .unwrap_or_default()
+    }
+
+    /// Returns since when the upstream is healthy, if it's currently healthy
+    pub fn healthy_since(&self, name: &str) -> Option<Instant> {
+        self.upstreams
+            .read()
+            .unwrap()
+            .get(name)
+            .filter(|health| health.status == HealthStatus::Healthy)
+            .and_then(|health| health.healthy_since)
+    }
+
+    /// Returns whether the upstream, after having been healthy, has been unhealthy for at least the
+    /// given duration
+    fn unhealthy_for(&self, name: &str, duration: Duration) -> bool {
+        self.upstreams
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|health| {
+                health.status == HealthStatus::Unhealthy
+                    && health.has_been_healthy
+                    && health
+                        .unhealthy_since
+                        .is_some_and(|since| since.elapsed() >= duration)
+            })
+    }
+
+    /// Removes the upstreams that are no longer configured e.g. after a configuration reload
+    fn retain(&self, names: &[&String]) {
+        self.upstreams
+            .write()
+            .unwrap()
+            .retain(|name, _| names.contains(&name));
+    }
+
+    /// Removes all the upstreams, as their health is unknown if health checks are disabled
+    fn clear(&self) {
+        self.upstreams.write().unwrap().clear();
+    }
+}
+
+/// Startup gate that opens once all the upstreams serving either a route or a model have answered
+/// `/v1/models` successfully, meaning that those are ready to serve requests (e.g. the model
+/// weights have been loaded); once opened it stays open for the lifetime of the proxy
+#[derive(Debug, Clone, Default)]
+pub struct StartupGate {
+    open: Arc<AtomicBool>,
+}
+
+impl StartupGate {
+    /// Returns whether all the upstreams have been ready at least once
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Opens the gate, once all the upstreams have been ready
+    pub fn open(&self) {
+        self.open.store(true, Ordering::Release);
+    }
+}
+
+/// Interval in seconds between the `/v1/models` checks until all the upstreams are ready
+const STARTUP_CHECK_INTERVAL_SECS: u64 = 1;
+
+/// Checks `/v1/models` on all the upstreams serving either a route or a model, until all of those
+/// respond successfully (or at least one of the upstreams of each pool), and then opens the
+/// startup gate.
+pub async fn wait_for_upstreams(state: ProxyState) {
+    let mut ready = BTreeSet::new();
+
+    loop {
+        let config = state.config.load_full();
+        // A pool is ready as soon as any of its upstreams is ready
+        let pending = config
+            .routed_upstreams()
+            .into_iter()
+            .map(|name| config.pool_members(name))
+            .filter(|members| !members.iter().any(|name| ready.contains(*name)))
+            .flatten()
+            .collect::<BTreeSet<_>>();
+
+        if pending.is_empty() {
+            tracing::info!("All the upstreams are ready, accepting requests");
+            state.startup.open();
+            return;
+        }
+
+        let mut checks = JoinSet::new();
+        for name in pending {
+            let upstream = &config.upstreams[name];
+            let Ok(uri) = build_upstream_uri(&upstream.host, upstream.port) else {
+                continue;
+            };
+            let client = state.client.clone();
+            let name = name.clone();
+            let uri = append_path_to_uri(uri, "/v1/models");
+            let timeout = Duration::from_secs(config.health_check.timeout_secs);
+            checks.spawn(async move { (name, check_upstream(client, uri, timeout).await) });
+        }
+
+        while let Some(res) = checks.join_next().await {
+            // A check that panicked only loses its own result, rather than the rest of the round
+            let Ok((name, result)) = res else {
+                continue;
+            };
+            match result {
+                Ok(()) => {
+                    tracing::info!("Upstream '{name}' is ready");
+                    ready.insert(name);
+                }
+                Err(e) => tracing::debug!("Upstream '{name}' is not ready yet: {e}"),
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(STARTUP_CHECK_INTERVAL_SECS)).await;
+    }
+}
+
+/// Returns the first upstream (or pool) serving either a route or a model that can't serve
+/// requests, where a pool can as long as any of its upstreams is up, and the upstreams no route
+/// uses, the fallbacks and the shadow upstreams (whose responses are discarded anyway) are ignored
+pub fn unservable_upstream(config: &Config, is_up: impl Fn(&str) -> bool) -> Option<&String> {
+    config.routed_upstreams().into_iter().find(|name| {
+        !config
+            .pool_members(name)
+            .into_iter()
+            .any(|name| is_up(name))
+    })
+}
+
+/// Runs the health checks against all the configured upstreams periodically, re-reading the
+/// configuration before every round, so that both the upstreams and the health check settings
+/// can be changed on reload. If `exit_after_unhealthy_secs` is set, then the process exits once a
+/// route can no longer be served, i.e. once its upstream (or every upstream of its pool) that has
+/// been healthy stays unhealthy for longer than that.
+pub async fn run_health_checks(state: ProxyState) {
+    loop {
+        let config = state.config.load_full();
+        let health_check = &config.health_check;
+
+        if health_check.enabled {
+            let mut checks = JoinSet::new();
+            for (name, upstream) in &config.upstreams {
+                let Ok(uri) = build_upstream_uri(&upstream.host, upstream.port) else {
+                    continue;
+                };
+                let client = state.client.clone();
+                let name = name.clone();
+                let uri = append_path_to_uri(uri, &health_check.path);
+                let timeout = Duration::from_secs(health_check.timeout_secs);
+                checks.spawn(async move { (name, check_upstream(client, uri, timeout).await) });
+            }
+
+            while let Some(res) = checks.join_next().await {
+                let Ok((name, result)) = res else {
+                    continue;
+                };
+                state.health.record(
+                    &name,
+                    result,
+                    health_check.unhealthy_threshold,
+                    health_check.healthy_threshold,
+                );
+            }
+
+            if let Some(secs) = health_check.exit_after_unhealthy_secs {
+                let exit_after = Duration::from_secs(secs);
+                if let Some(name) = unservable_upstream(&config, |name| {
+                    !state.health.unhealthy_for(name, exit_after)
+                }) {
+                    tracing::error!(
+                        "Upstream '{name}' has been unhealthy for more than {secs}s, exiting"
+                    );
+                    std::process::exit(1);
+                }
+            }
+
+            state
+                .health
+                .retain(&config.upstreams.keys().collect::<Vec<_>>());
+        } else {
+            state.health.clear();
+        }
+
+        tokio::time::sleep(Duration::from_secs(health_check.interval_secs)).await;
+    }
+}
+
+/// Sends a GET request to the health check endpoint of the upstream, considering it healthy only
+/// if it responds with a 2XX status within the timeout
+async fn check_upstream(client: HttpClient, uri: Uri, timeout: Duration) -> Result<(), String> {
+    let req: Request<Body> = Request::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    match tokio::time::timeout(timeout, client.request(req)).await {
+        Ok(Ok(res)) if res.status().is_success() => Ok(()),
+        Ok(Ok(res)) => Err(format!("unexpected status {}", res.status())),
+        Ok(Err(e)) => Err(match e.source() {
+            Some(source) => format!("{e}: {source}"),
+            None => e.to_string(),
+        }),
+        Err(_) => Err(format!("timed out after {}s", timeout.as_secs())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_thresholds() {
+        let registry = HealthRegistry::default();
+
+        let health = registry.record("vllm", Err("down".to_string()), 2, 1);
+        assert_eq!(health.status, HealthStatus::Unknown);
+        let health = registry.record("vllm", Err("down".to_string()), 2, 1);
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert!(!health.has_been_healthy);
+
+        let health = registry.record("vllm", Ok(()), 2, 1);
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.has_been_healthy);
+        assert!(health.unhealthy_since.is_none());
+    }
+
+    #[test]
+    fn test_unservable_upstream() {
+        let state = ProxyState::from_toml(
+            r#"
+            [upstreams.vllm]
+            port = 8080
+
+            [upstreams.vllm-1]
+            port = 8081
+
+            [upstreams.unused]
+            port = 8082
+
+            [upstreams.candidate]
+            port = 8083
+
+            [pools.vllm-replicas]
+            upstreams = ["vllm", "vllm-1"]
+
+            [routes.chat-completions]
+            upstream = "vllm-replicas"
+            shadow = { upstream = "candidate", percent = 5 }
+            "#,
+        );
+        let config = state.config.load_full();
+        let down_for = |name| {
+            state.health.record(name, Ok(()), 1, 1);
+            state.health.record(name, Err("down".to_string()), 1, 1);
+        };
+        let unservable = || {
+            unservable_upstream(&config, |name| {
+                !state.health.unhealthy_for(name, Duration::ZERO)
+            })
+            .cloned()
+        };
+
+        // The proxy doesn't exit while the other replica of the pool can serve the route, nor when
+        // either an upstream that no route uses or the shadow upstream is down
+        down_for("vllm");
+        down_for("unused");
+        down_for("candidate");
+        assert_eq!(unservable(), None);
+
+        down_for("vllm-1");
+        assert_eq!(unservable(), Some("vllm-replicas".to_string()));
+
+        // The upstreams that have never been healthy are still starting, rather than down
+        state.health.record("vllm", Ok(()), 1, 1);
+        state.health.record("vllm-1", Ok(()), 1, 1);
+        assert!(!state.health.unhealthy_for("new", Duration::ZERO));
+        state.health.record("new", Err("down".to_string()), 1, 1);
+        assert!(!state.health.unhealthy_for("new", Duration::ZERO));
+        assert_eq!(unservable(), None);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_upstreams_opens_gate() {
+        use crate::utils::serve_locally;
+        use axum::{http::StatusCode, routing::get, Router};
+
+        // The upstream only answers `/v1/models` once it has loaded the model
+        let loaded = Arc::new(AtomicBool::new(false));
+        let uri = serve_locally(Router::new().route(
+            "/v1/models",
+            get({
+                let loaded = loaded.clone();
+                || async move {
+                    match loaded.load(Ordering::Acquire) {
+                        true => StatusCode::OK,
+                        false => StatusCode::SERVICE_UNAVAILABLE,
+                    }
+                }
+            }),
+        ))
+        .await;
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.vllm]
+            host = "127.0.0.1"
+            port = {}
+
+            [routes.chat-completions]
+            upstream = "vllm"
+            "#,
+            uri.port_u16().unwrap()
+        ));
+
+        tokio::spawn(wait_for_upstreams(state.clone()));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!state.startup.is_open());
+
+        loaded.store(true, Ordering::Release);
+        for _ in 0..50 {
+            if state.startup.is_open() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(state.startup.is_open());
+    }
+}
@@ -0,0 +1,483 @@
+use crate::{
+    circuit_breaker::{CircuitBreakers, CircuitState},
+    config::{LoadBalancingStrategy, PoolConfig},
+    engine_metrics::EngineLoad,
+    health_check::{HealthRegistry, HealthStatus},
+    utils::{random_u64, stable_hash},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Minimum share of the traffic sent to an upstream that just became healthy again, while in
+/// slow start
+const SLOW_START_MIN_WEIGHT: f64 = 0.1;
+
+/// Number of scrape intervals after which the load scraped from an upstream is stale
+const STALE_LOAD_INTERVALS: u32 = 3;
+
+/// An upstream of a pool that can be picked, with its current load and weight
+#[derive(Debug, Clone)]
+struct Candidate<'a> {
+    name: &'a str,
+    in_flight: usize,
+    /// Between `SLOW_START_MIN_WEIGHT` and 1, lower while the upstream is in slow start
+    weight: f64,
+    /// The load of the inference engine, if scraped recently with the `least-loaded` strategy
+    engine_load: Option<f64>,
+}
+
+impl Candidate<'_> {
+    /// Returns the load of the upstream relative to its weight, so that the upstreams in slow
+    /// start look busier than they are
+    fn load(&self) -> f64 {
+        (self.in_flight + 1) as f64 / self.weight
+    }
+}
+
+/// The load last scraped from an upstream, along with the requests in flight to it back then
+#[derive(Debug, Clone, Copy)]
+struct ScrapedLoad {
+    load: EngineLoad,
+    scraped_at: Instant,
+    in_flight: usize,
+}
+
+impl ScrapedLoad {
+    /// Returns the load of the engine, where the waiting requests weigh twice as much as the
+    /// running ones, scaled up by the usage of the KV cache as the engine is about to start
+    /// preempting or queueing requests when full. The requests sent since the scrape are counted
+    /// as waiting, so that a burst is not sent to the same upstream until the next scrape
+    fn estimate(&self, in_flight: usize) -> f64 {
+        let waiting = self.load.waiting + in_flight.saturating_sub(self.in_flight) as f64;
+        (self.load.running + 2.0 * waiting + 1.0)
+            * (1.0 + self.load.kv_cache_usage.unwrap_or_default())
+    }
+}
+
+/// Balances the requests across the upstreams of each pool, ejecting the ones that are either
+/// unhealthy or with an open circuit, and keeping track of the requests in flight to each upstream
+#[derive(Debug, Clone, Default)]
+pub struct LoadBalancer {
+    next: Arc<Mutex<HashMap<String, usize>>>,
+    in_flight: Arc<Mutex<HashMap<String, usize>>>,
+    loads: Arc<Mutex<HashMap<String, ScrapedLoad>>>,
+}
+
+impl LoadBalancer {
+    /// Picks the upstream of the pool to send the request to, out of the upstreams that are
+    /// neither unhealthy nor with an open circuit, or out of all of those if none is available.
+    /// The `prefix` of the request (if any) is only computed for the `prefix-hash` strategy, which
+    /// falls back to the least in flight upstream for the requests without one
+    pub fn pick(
+        &self,
+        pool_name: &str,
+        pool: &PoolConfig,
+        prefix: Option<&dyn Fn(usize) -> String>,
+        health: &HealthRegistry,
+        circuit_breakers: &CircuitBreakers,
+    ) -> Option<String> {
+        let now = Instant::now();
+        let stale_after = Duration::from_secs(pool.scrape_interval_secs) * STALE_LOAD_INTERVALS;
+        let in_flight = self.in_flight.lock().unwrap();
+        let loads = self.loads.lock().unwrap();
+        let all = pool
+            .upstreams
+            .iter()
+            .map(|name| {
+                let in_flight = in_flight.get(name).copied().unwrap_or_default();
+                let engine_load = loads
+                    .get(name)
+                    .filter(|_| pool.strategy == LoadBalancingStrategy::LeastLoaded)
+                    .filter(|load| now.saturating_duration_since(load.scraped_at) < stale_after)
+                    .map(|load| load.estimate(in_flight));
+                Candidate {
+                    name,
+                    in_flight,
+                    weight: slow_start_weight(
+                        health.healthy_since(name),
+                        pool.slow_start_secs,
+                        now,
+                    ),
+                    engine_load,
+                }
+            })
+            .collect::<Vec<_>>();
+        drop((in_flight, loads));
+
+        let available = all
+            .iter()
+            .filter(|candidate| {
+                health.status(candidate.name) != HealthStatus::Unhealthy
+                    && circuit_breakers.state(candidate.name) != CircuitState::Open
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let candidates = if available.is_empty() {
+            tracing::warn!("No upstream available in pool '{pool_name}', trying all of those");
+            all
+        } else {
+            available
+        };
+
+        if candidates.len() <= 1 {
+            return candidates
+                .first()
+                .map(|candidate| candidate.name.to_string());
+        }
+
+        let index = match (pool.strategy, prefix) {
+            (LoadBalancingStrategy::PrefixHash, Some(prefix)) => prefix_hash(
+                &prefix(pool.prefix_chars),
+                &candidates,
+                pool.max_load_factor,
+            ),
+            (LoadBalancingStrategy::PrefixHash, None) => least_in_flight(&candidates),
+            (strategy, _) => self.choose(pool_name, strategy, &candidates),
+        };
+        Some(candidates[index].name.to_string())
+    }
+
+    /// Returns the index of the candidate picked with the strategy, out of at least two
+    fn choose(
+        &self,
+        pool_name: &str,
+        strategy: LoadBalancingStrategy,
+        candidates: &[Candidate],
+    ) -> usize {
+        match strategy {
+            LoadBalancingStrategy::RoundRobin => {
+                let start = {
+                    let mut next = self.next.lock().unwrap();
+                    let next = next.entry(pool_name.to_string()).or_default();
+                    *next = next.wrapping_add(1);
+                    next.wrapping_sub(1)
+                };
+                // The upstreams in slow start are skipped with a probability based on its weight
+                (0..candidates.len())
+                    .map(|offset| (start + offset) % candidates.len())
+                    .find(|index| {
+                        let weight = candidates[*index].weight;
+                        weight >= 1.0 || (random_u64() % 1000) as f64 / 1000.0 < weight
+                    })
+                    .unwrap_or(start % candidates.len())
+            }
+            LoadBalancingStrategy::LeastInFlight | LoadBalancingStrategy::PrefixHash => {
+                least_in_flight(candidates)
+            }
+            // The loads are only compared if recent for all the upstreams, as otherwise those
+            // are not comparable to the requests in flight of the rest
+            LoadBalancingStrategy::LeastLoaded => {
+                match candidates
+                    .iter()
+                    .map(|candidate| Some(candidate.engine_load? / candidate.weight))
+                    .collect::<Option<Vec<_>>>()
+                {
+                    Some(loads) => (0..loads.len())
+                        .min_by(|a, b| loads[*a].total_cmp(&loads[*b]))
+                        .unwrap_or_default(),
+                    None => {
+                        tracing::debug!(
+                            "Stale engine metrics in pool '{pool_name}', picking the upstream with the fewest requests in flight"
+                        );
+                        least_in_flight(candidates)
+                    }
+                }
+            }
+            LoadBalancingStrategy::PowerOfTwoChoices => {
+                let first = (random_u64() % candidates.len() as u64) as usize;
+                let offset = 1 + (random_u64() % (candidates.len() as u64 - 1)) as usize;
+                let second = (first + offset) % candidates.len();
+                if candidates[second].load() < candidates[first].load() {
+                    second
+                } else {
+                    first
+                }
+            }
+        }
+    }
+
+    /// Records the load scraped from the inference engine of the upstream
+    pub fn record_load(&self, upstream: &str, load: EngineLoad) {
+        let in_flight = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .copied()
+            .unwrap_or_default();
+        self.loads.lock().unwrap().insert(
+            upstream.to_string(),
+            ScrapedLoad {
+                load,
+                scraped_at: Instant::now(),
+                in_flight,
+            },
+        );
+    }
+
+    /// Removes the loads of the upstreams that are no longer scraped e.g. after a reload
+    pub fn retain_loads(&self, names: &[&String]) {
+        self.loads
+            .lock()
+            .unwrap()
+            .retain(|name, _| names.contains(&name));
+    }
+
+    /// Tracks a request in flight to the upstream, until the returned guard is dropped
+    pub fn start(&self, upstream: &str) -> InFlight {
+        *self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_default() += 1;
+        InFlight {
+            balancer: self.clone(),
+            upstream: upstream.to_string(),
+        }
+    }
+}
+
+/// Returns the index of the candidate with the fewest requests in flight relative to its weight
+fn least_in_flight(candidates: &[Candidate]) -> usize {
+    (0..candidates.len())
+        .min_by(|a, b| {
+            candidates[*a]
+                .load()
+                .total_cmp(&candidates[*b].load())
+                // Ties are broken at random, so that the first upstream is not favoured
+                .then_with(|| (random_u64() % 3).cmp(&1))
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the index of the candidate the prefix is hashed to, via rendezvous hashing so that
+/// only the prefixes of an upstream move when the upstreams change, with bounded loads i.e. the
+/// upstreams with more than `max_load_factor` times the average requests in flight are skipped in
+/// favour of the next one for the prefix
+fn prefix_hash(prefix: &str, candidates: &[Candidate], max_load_factor: f64) -> usize {
+    let prefix = stable_hash(prefix.as_bytes()).to_le_bytes();
+
+    let mut ranked = candidates
+        .iter()
+        .enumerate()
+        .map(|(index, candidate)| {
+            let key = [&prefix[..], candidate.name.as_bytes()].concat();
+            (stable_hash(&key), index)
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_unstable_by(|a, b| b.cmp(a));
+
+    let in_flight = candidates
+        .iter()
+        .map(|candidate| candidate.in_flight)
+        .sum::<usize>();
+    let capacity = max_load_factor * (in_flight + 1) as f64 / candidates.len() as f64;
+    let index = ranked
+        .iter()
+        .map(|(_, index)| *index)
+        .find(|index| {
+            let candidate = &candidates[*index];
+            ((candidate.in_flight + 1) as f64) <= (capacity * candidate.weight).ceil()
+        })
+        .unwrap_or(ranked[0].1);
+    if index != ranked[0].1 {
+        tracing::debug!(
+            "Upstream '{}' is overloaded, spilling the request over to '{}'",
+            candidates[ranked[0].1].name,
+            candidates[index].name
+        );
+    }
+    index
+}
+
+/// Returns the weight of an upstream, ramping up linearly from `SLOW_START_MIN_WEIGHT` to 1 over
+/// the slow start once it became healthy
+fn slow_start_weight(healthy_since: Option<Instant>, slow_start_secs: u64, now: Instant) -> f64 {
+    let slow_start = Duration::from_secs(slow_start_secs);
+    match healthy_since {
+        Some(since) if now.saturating_duration_since(since) < slow_start => {
+            let ramp =
+                now.saturating_duration_since(since).as_secs_f64() / slow_start.as_secs_f64();
+            ramp.max(SLOW_START_MIN_WEIGHT)
+        }
+        _ => 1.0,
+    }
+}
+
+/// A request in flight to an upstream, tracked until dropped
+#[derive(Debug)]
+pub struct InFlight {
+    balancer: LoadBalancer,
+    upstream: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(in_flight) = self
+            .balancer
+            .in_flight
+            .lock()
+            .unwrap()
+            .get_mut(&self.upstream)
+        {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn pool(strategy: LoadBalancingStrategy) -> PoolConfig {
+        PoolConfig {
+            upstreams: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            strategy,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_robin_ejects_unhealthy() {
+        let balancer = LoadBalancer::default();
+        let health = HealthRegistry::default();
+        let circuit_breakers = CircuitBreakers::default();
+        let pool = pool(LoadBalancingStrategy::RoundRobin);
+
+        let picks = (0..6)
+            .map(|_| {
+                balancer
+                    .pick("pool", &pool, None, &health, &circuit_breakers)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(picks, ["a", "b", "c", "a", "b", "c"]);
+
+        health.record("b", Err("down".to_string()), 1, 1);
+        for _ in 0..6 {
+            assert_ne!(
+                balancer.pick("pool", &pool, None, &health, &circuit_breakers),
+                Some("b".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_least_in_flight() {
+        let balancer = LoadBalancer::default();
+        let health = HealthRegistry::default();
+        let circuit_breakers = CircuitBreakers::default();
+
+        for strategy in [
+            LoadBalancingStrategy::LeastInFlight,
+            LoadBalancingStrategy::PowerOfTwoChoices,
+        ] {
+            let pool = pool(strategy);
+            let _a = (0..2).map(|_| balancer.start("a")).collect::<Vec<_>>();
+            let _b = (0..2).map(|_| balancer.start("b")).collect::<Vec<_>>();
+            // Either way, the upstream with the most requests in flight is never picked
+            let _c = (0..4).map(|_| balancer.start("c")).collect::<Vec<_>>();
+            for _ in 0..10 {
+                assert_ne!(
+                    balancer.pick("pool", &pool, None, &health, &circuit_breakers),
+                    Some("c".to_string())
+                );
+            }
+        }
+        assert!(balancer.in_flight.lock().unwrap().values().all(|n| *n == 0));
+    }
+
+    #[test]
+    fn test_prefix_hash_with_bounded_load() {
+        let balancer = LoadBalancer::default();
+        let health = HealthRegistry::default();
+        let circuit_breakers = CircuitBreakers::default();
+        let pool = pool(LoadBalancingStrategy::PrefixHash);
+        let pick = |prefix: &str| {
+            let prefix = |chars: usize| prefix.chars().take(chars).collect::<String>();
+            balancer
+                .pick("pool", &pool, Some(&prefix), &health, &circuit_breakers)
+                .unwrap()
+        };
+
+        // The requests sharing a prefix stick to the same upstream, while the rest are spread
+        let upstream = pick("You are a pirate");
+        assert!((0..10).all(|_| pick("You are a pirate") == upstream));
+        let upstreams = (0..30)
+            .map(|i| pick(&format!("You are pirate #{i}")))
+            .collect::<HashSet<_>>();
+        assert_eq!(upstreams.len(), 3);
+
+        // Until the upstream has too many requests in flight, spilling over to the next one
+        let in_flight = (0..3)
+            .map(|_| balancer.start(&upstream))
+            .collect::<Vec<_>>();
+        let spillover = pick("You are a pirate");
+        assert_ne!(spillover, upstream);
+        assert!((0..10).all(|_| pick("You are a pirate") == spillover));
+        drop(in_flight);
+        assert_eq!(pick("You are a pirate"), upstream);
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        let balancer = LoadBalancer::default();
+        let health = HealthRegistry::default();
+        let circuit_breakers = CircuitBreakers::default();
+        let pool = pool(LoadBalancingStrategy::LeastLoaded);
+        let pick = || {
+            balancer
+                .pick("pool", &pool, None, &health, &circuit_breakers)
+                .unwrap()
+        };
+
+        let load = |running, waiting, kv_cache_usage| EngineLoad {
+            running,
+            waiting,
+            kv_cache_usage: Some(kv_cache_usage),
+        };
+        balancer.record_load("a", load(8.0, 2.0, 0.9));
+        balancer.record_load("b", load(8.0, 0.0, 0.5));
+        // Not scraped yet, so all the upstreams are balanced on the requests in flight instead
+        let _c = (0..2).map(|_| balancer.start("c")).collect::<Vec<_>>();
+        assert_ne!(pick(), "c");
+
+        balancer.record_load("c", load(8.0, 0.0, 0.2));
+        assert_eq!(pick(), "c");
+        // The requests sent since the last scrape are counted as waiting
+        let _c = (0..4).map(|_| balancer.start("c")).collect::<Vec<_>>();
+        assert_eq!(pick(), "b");
+
+        // Until stale, falling back to the upstream with the fewest requests in flight
+        let _b = balancer.start("b");
+        balancer
+            .loads
+            .lock()
+            .unwrap()
+            .get_mut("b")
+            .unwrap()
+            .scraped_at -= Duration::from_secs(pool.scrape_interval_secs * 3);
+        assert_eq!(pick(), "a");
+    }
+
+    #[test]
+    fn test_slow_start_weight() {
+        let now = Instant::now();
+        assert_eq!(slow_start_weight(None, 60, now), 1.0);
+        assert_eq!(slow_start_weight(Some(now), 60, now), SLOW_START_MIN_WEIGHT);
+        assert_eq!(
+            slow_start_weight(Some(now), 60, now + Duration::from_secs(30)),
+            0.5
+        );
+        assert_eq!(
+            slow_start_weight(Some(now), 60, now + Duration::from_secs(60)),
+            1.0
+        );
+        assert_eq!(slow_start_weight(Some(now), 0, now), 1.0);
+    }
+}
@@ -23,19 +23,51 @@
 //!     --upstream-host 0.0.0.0 --upstream-port 8080 \
 //!     --upstream-type chat-completions
 //! ```
+//!
+//! Alternatively, the proxy can be configured via a TOML or YAML file with `--config`, which allows
+//! to define multiple upstreams, model aliases, API keys, limits and logging, whilst the CLI
+//! arguments and environment variables still take precedence over the values within the file.
+//!
+//! ```
+//! azure-openai-proxy --config config.toml --print-config
+//! ```
 
 use clap::{Parser, ValueEnum};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
+mod cache;
+mod circuit_breaker;
+mod concurrency;
+mod config;
+mod connector;
+mod embeddings_batcher;
+mod embeddings_cache;
+mod embeddings_response;
+mod embeddings_splitter;
+mod engine_metrics;
 mod errors;
 mod handlers;
+mod health_check;
+mod load_balancer;
+mod metrics;
+mod middlewares;
 mod proxy;
+mod rate_limit;
 mod schemas;
+mod shadow;
+mod telemetry;
+mod timeout;
+mod traffic_split;
+mod upstream;
 mod utils;
 
+use config::Config;
 use proxy::start_server;
 
-#[derive(ValueEnum, Clone, Default, Debug, Serialize)]
+#[derive(
+    ValueEnum, Clone, Default, Debug, Serialize, Deserialize, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
 #[serde(rename_all = "kebab-case")]
 pub enum UpstreamType {
     #[default]
@@ -43,35 +75,77 @@ pub enum UpstreamType {
     Embeddings,
 }
 
-#[derive(Parser)]
+impl UpstreamType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ChatCompletions => "chat-completions",
+            Self::Embeddings => "embeddings",
+        }
+    }
+}
+
+#[derive(Parser, Debug, Default)]
 #[command(name = "azure-openai-proxy", version, about)]
 struct Cli {
-    #[arg(short, long, env, default_value = "0.0.0.0")]
-    host: String,
+    /// Path to the TOML or YAML configuration file, the CLI arguments and environment variables
+    /// take precedence over the values defined within the file
+    #[arg(short, long, env)]
+    config: Option<PathBuf>,
+
+    #[arg(long, env)]
+    host: Option<String>,
 
-    #[arg(short, long, env, default_value = "80")]
-    port: u16,
+    #[arg(short, long, env)]
+    port: Option<u16>,
 
-    #[arg(short, long, env, default_value = "0.0.0.0")]
-    upstream_host: String,
+    #[arg(long, env)]
+    upstream_host: Option<String>,
 
-    #[arg(short, long, env, default_value = "8080")]
-    upstream_port: u16,
+    #[arg(long, env)]
+    upstream_port: Option<u16>,
 
-    #[arg(short, long, env)]
-    upstream_type: UpstreamType,
+    #[arg(long, env)]
+    upstream_type: Option<UpstreamType>,
+
+    /// Base URL of the OpenTelemetry collector to export the spans to over OTLP/HTTP e.g.
+    /// `http://localhost:4318`
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Exits the proxy when an upstream stays unhealthy for longer than the given seconds, once
+    /// it has been healthy at least once, so that the container is restarted
+    #[arg(long, env)]
+    exit_after_unhealthy_secs: Option<u64>,
+
+    /// Prints the effective configuration (after merging the configuration file, the environment
+    /// variables and the CLI arguments) and exits
+    #[arg(long)]
+    print_config: bool,
 }
 
 /// Entrypoint for the binary, that runs the Axum proxy
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
-    start_server(
-        Some(&args.host),
-        Some(&args.port),
-        &args.upstream_host,
-        Some(&args.upstream_port),
-        &args.upstream_type,
-    )
-    .await;
+
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    if args.print_config {
+        match config.to_redacted_toml() {
+            Ok(config) => print!("{config}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    start_server(config, args).await;
 }
@@ -0,0 +1,414 @@
+use crate::schemas::usage::Usage;
+use prometheus::{
+    exponential_buckets, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Prefix of all the metrics exposed by the proxy
+const NAMESPACE: &str = "azure_openai_proxy";
+
+/// Prometheus metrics of the proxy, shared across all the proxy endpoints
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    time_to_first_token: HistogramVec,
+    upstream_errors: IntCounterVec,
+    in_flight: IntGaugeVec,
+    prompt_tokens: IntCounterVec,
+    completion_tokens: IntCounterVec,
+    upstream_in_flight: IntGaugeVec,
+    upstream_queued: IntGaugeVec,
+    upstream_rejected: IntCounterVec,
+    upstream_retries: IntCounterVec,
+    upstream_circuit_state: IntGaugeVec,
+    upstream_failovers: IntCounterVec,
+    variant_requests: IntCounterVec,
+    variant_request_duration: HistogramVec,
+    shadow_requests: IntCounterVec,
+    shadow_request_duration: HistogramVec,
+    shadow_requests_dropped: IntCounterVec,
+    cache_requests: IntCounterVec,
+    embeddings_batch_requests: Histogram,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)
+            .expect("Metrics namespace should be valid");
+        // Buckets from 5ms up to ~164s, as generations can take minutes to complete
+        let buckets = exponential_buckets(0.005, 2.0, 16).expect("Buckets should be valid");
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Total number of requests"),
+            &["route", "status", "model"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Duration of the requests until the response body is fully sent",
+            )
+            .buckets(buckets.clone()),
+            &["route", "status"],
+        )
+        .unwrap();
+        let time_to_first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "time_to_first_token_seconds",
+                "Time until the first event of the streamed responses is received",
+            )
+            .buckets(buckets.clone()),
+            &["route", "model"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new(
+                "upstream_errors_total",
+                "Total number of failed upstream requests by error class",
+            ),
+            &["upstream", "class"],
+        )
+        .unwrap();
+        let in_flight = IntGaugeVec::new(
+            Opts::new("in_flight_requests", "Number of requests being served"),
+            &["route"],
+        )
+        .unwrap();
+        let prompt_tokens = IntCounterVec::new(
+            Opts::new(
+                "prompt_tokens_total",
+                "Total number of prompt tokens reported by the upstreams",
+            ),
+            &["route", "model"],
+        )
+        .unwrap();
+        let completion_tokens = IntCounterVec::new(
+            Opts::new(
+                "completion_tokens_total",
+                "Total number of completion tokens reported by the upstreams",
+            ),
+            &["route", "model"],
+        )
+        .unwrap();
+
+        let upstream_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "upstream_in_flight_requests",
+                "Number of requests in flight to the upstreams with a concurrency limit",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+
+        let upstream_failovers = IntCounterVec::new(
+            Opts::new(
+                "upstream_failovers_total",
+                "Total number of requests failed over from an upstream to its fallback",
+            ),
+            &["upstream", "fallback"],
+        )
+        .unwrap();
+
+        let variant_requests = IntCounterVec::new(
+            Opts::new(
+                "variant_requests_total",
+                "Total number of requests by traffic split and variant",
+            ),
+            &["split", "variant", "status"],
+        )
+        .unwrap();
+        let variant_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "variant_request_duration_seconds",
+                "Duration of the requests by traffic split and variant",
+            )
+            .buckets(buckets.clone()),
+            &["split", "variant"],
+        )
+        .unwrap();
+
+        let shadow_requests = IntCounterVec::new(
+            Opts::new(
+                "shadow_requests_total",
+                "Total number of requests mirrored to the shadow upstreams by status",
+            ),
+            &["upstream", "status"],
+        )
+        .unwrap();
+        let shadow_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "shadow_request_duration_seconds",
+                "Duration of the requests mirrored to the shadow upstreams",
+            )
+            .buckets(buckets),
+            &["upstream"],
+        )
+        .unwrap();
+        let shadow_requests_dropped = IntCounterVec::new(
+            Opts::new(
+                "shadow_requests_dropped_total",
+                "Total number of requests not mirrored as too many shadow requests were in flight",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let cache_requests = IntCounterVec::new(
+            Opts::new(
+                "cache_requests_total",
+                "Total number of cacheable requests by result i.e. hit, partial, miss or bypass",
+            ),
+            &["route", "result"],
+        )
+        .unwrap();
+        let embeddings_batch_requests = Histogram::with_opts(
+            HistogramOpts::new(
+                "embeddings_batch_requests",
+                "Number of embeddings requests batched into each upstream request",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 8).expect("Buckets should be valid")),
+        )
+        .unwrap();
+        let upstream_queued = IntGaugeVec::new(
+            Opts::new(
+                "upstream_queued_requests",
+                "Number of requests waiting for the upstreams with a concurrency limit",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+        let upstream_rejected = IntCounterVec::new(
+            Opts::new(
+                "upstream_rejected_requests_total",
+                "Total number of requests rejected while waiting for the upstreams by reason",
+            ),
+            &["upstream", "reason"],
+        )
+        .unwrap();
+
+        let upstream_retries = IntCounterVec::new(
+            Opts::new(
+                "upstream_retries_total",
+                "Total number of retried upstream requests by reason",
+            ),
+            &["upstream", "reason"],
+        )
+        .unwrap();
+
+        let upstream_circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "upstream_circuit_state",
+                "State of the circuit breaker of the upstreams, 0 closed, 1 half-open and 2 open",
+            ),
+            &["upstream"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(request_duration.clone()),
+            Box::new(time_to_first_token.clone()),
+            Box::new(upstream_errors.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(prompt_tokens.clone()),
+            Box::new(completion_tokens.clone()),
+            Box::new(upstream_in_flight.clone()),
+            Box::new(upstream_queued.clone()),
+            Box::new(upstream_rejected.clone()),
+            Box::new(upstream_retries.clone()),
+            Box::new(upstream_circuit_state.clone()),
+            Box::new(upstream_failovers.clone()),
+            Box::new(variant_requests.clone()),
+            Box::new(variant_request_duration.clone()),
+            Box::new(shadow_requests.clone()),
+            Box::new(shadow_request_duration.clone()),
+            Box::new(shadow_requests_dropped.clone()),
+            Box::new(cache_requests.clone()),
+            Box::new(embeddings_batch_requests.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("Metrics should be registered once");
+        }
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            time_to_first_token,
+            upstream_errors,
+            in_flight,
+            prompt_tokens,
+            completion_tokens,
+            upstream_in_flight,
+            upstream_queued,
+            upstream_rejected,
+            upstream_retries,
+            upstream_circuit_state,
+            upstream_failovers,
+            variant_requests,
+            variant_request_duration,
+            shadow_requests,
+            shadow_request_duration,
+            shadow_requests_dropped,
+            cache_requests,
+            embeddings_batch_requests,
+        }
+    }
+}
+
+impl Metrics {
+    /// Encodes all the metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+
+    /// Records the start of a request, which is in-flight until recorded as completed
+    pub fn record_request_start(&self, route: &str) {
+        self.in_flight.with_label_values(&[route]).inc();
+    }
+
+    /// Records a completed request, where the model is the one reported by the upstream (if any),
+    /// along with the token usage (if reported)
+    pub fn record_request(
+        &self,
+        route: &str,
+        status: &str,
+        model: &str,
+        duration: Duration,
+        usage: Option<&Usage>,
+    ) {
+        self.in_flight.with_label_values(&[route]).dec();
+        self.requests
+            .with_label_values(&[route, status, model])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route, status])
+            .observe(duration.as_secs_f64());
+
+        if let Some(usage) = usage {
+            self.prompt_tokens
+                .with_label_values(&[route, model])
+                .inc_by(usage.prompt_tokens);
+            self.completion_tokens
+                .with_label_values(&[route, model])
+                .inc_by(usage.completion_tokens);
+        }
+    }
+
+    /// Records the time until the first event of a streamed response was received
+    pub fn record_time_to_first_token(&self, route: &str, model: &str, duration: Duration) {
+        self.time_to_first_token
+            .with_label_values(&[route, model])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a failed request to the upstream, where the class is one of `connect`, `request`,
+    /// `http_4xx` or `http_5xx`
+    pub fn record_upstream_error(&self, upstream: &str, class: &str) {
+        self.upstream_errors
+            .with_label_values(&[upstream, class])
+            .inc();
+    }
+
+    /// Records the change on the number of requests either in flight to or waiting for the
+    /// upstream, as limited by its `max_concurrent_requests`
+    pub fn record_upstream_admission(&self, upstream: &str, in_flight: i64, queued: i64) {
+        self.upstream_in_flight
+            .with_label_values(&[upstream])
+            .add(in_flight);
+        self.upstream_queued
+            .with_label_values(&[upstream])
+            .add(queued);
+    }
+
+    /// Records a request rejected before being sent to the upstream, where the reason is either
+    /// `queue_full`, `queue_timeout` or `circuit_open`
+    pub fn record_upstream_rejected(&self, upstream: &str, reason: &str) {
+        self.upstream_rejected
+            .with_label_values(&[upstream, reason])
+            .inc();
+    }
+
+    /// Records a retried request to the upstream, where the reason is either `connect` or the
+    /// retried status e.g. `http_503`
+    pub fn record_upstream_retry(&self, upstream: &str, reason: &str) {
+        self.upstream_retries
+            .with_label_values(&[upstream, reason])
+            .inc();
+    }
+
+    /// Records the state of the circuit breaker of the upstream
+    pub fn record_circuit_state(&self, upstream: &str, state: i64) {
+        self.upstream_circuit_state
+            .with_label_values(&[upstream])
+            .set(state);
+    }
+
+    /// Records a request failed over from the upstream to its fallback
+    pub fn record_upstream_failover(&self, upstream: &str, fallback: &str) {
+        self.upstream_failovers
+            .with_label_values(&[upstream, fallback])
+            .inc();
+    }
+
+    /// Records a completed request assigned to a variant of a traffic split
+    pub fn record_variant_request(
+        &self,
+        split: &str,
+        variant: &str,
+        status: &str,
+        duration: Duration,
+    ) {
+        self.variant_requests
+            .with_label_values(&[split, variant, status])
+            .inc();
+        self.variant_request_duration
+            .with_label_values(&[split, variant])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a completed request mirrored to the shadow upstream, where the status is `error`
+    /// if the shadow upstream did not respond
+    pub fn record_shadow_request(&self, upstream: &str, status: &str, duration: Duration) {
+        self.shadow_requests
+            .with_label_values(&[upstream, status])
+            .inc();
+        self.shadow_request_duration
+            .with_label_values(&[upstream])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a request that failed to be mirrored to the shadow upstream before being sent, e.g.
+    /// as it couldn't be built for it, which has no latency to observe
+    pub fn record_shadow_request_error(&self, upstream: &str) {
+        self.shadow_requests
+            .with_label_values(&[upstream, "error"])
+            .inc();
+    }
+
+    /// Records a request not mirrored to the shadow upstream, as too many were in flight already
+    pub fn record_shadow_request_dropped(&self, upstream: &str) {
+        self.shadow_requests_dropped
+            .with_label_values(&[upstream])
+            .inc();
+    }
+
+    /// Records a request eligible for the cache, by whether it was served from the cache (`hit`),
+    /// from the upstream (`miss`), from both for some of the embeddings inputs (`partial`), or the
+    /// client bypassed the cache (`bypass`)
+    pub fn record_cache_request(&self, route: &str, result: &str) {
+        self.cache_requests
+            .with_label_values(&[route, result])
+            .inc();
+    }
+
+    /// Records an upstream request with the given number of batched embeddings requests
+    pub fn record_embeddings_batch(&self, requests: usize) {
+        self.embeddings_batch_requests.observe(requests as f64);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::{
+    metrics::Metrics, middlewares::request_id::RequestId, proxy::ProxyState,
+    rate_limit::TokenReservation, schemas::usage::Usage, traffic_split::Variant,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{MatchedPath, Request, State},
+    http::{header::CONTENT_TYPE, Method, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use std::time::Instant;
+use tracing::Span;
+
+/// Maximum size in bytes of a non-streaming response body buffered to read the `usage` from, so
+/// that the token counts of larger responses (e.g. huge embeddings batches) are not recorded
+const MAX_USAGE_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Status recorded for the requests dropped before a response was produced e.g. as the client
+/// closed the connection, following the NGINX convention
+const CLIENT_CLOSED_REQUEST: &str = "499";
+
+/// The fields read from either the response body or each of the streamed events, as the model
+/// served by the upstream bounds the cardinality of the `model` label, unlike the one requested
+#[derive(Deserialize, Debug, Default)]
+struct ResponseSummary {
+    model: Option<String>,
+    usage: Option<Usage>,
+}
+
+/// Observes the response body as it's sent to the client, and once dropped (i.e. once the body
+/// has been fully sent or the client closed the connection) writes the access log, records the
+/// metrics of the request, and records both the model and the token usage on the request span
+struct ResponseObserver {
+    metrics: Metrics,
+    span: Span,
+    method: Method,
+    route: String,
+    request_id: Option<RequestId>,
+    start: Instant,
+    status: Option<StatusCode>,
+    streaming: bool,
+    buffer: Vec<u8>,
+    truncated: bool,
+    first_event: bool,
+    model: Option<String>,
+    usage: Option<Usage>,
+    reservation: Option<TokenReservation>,
+    variant: Option<Variant>,
+}
+
+impl ResponseObserver {
+    fn new(metrics: Metrics, method: Method, route: String, request_id: Option<RequestId>) -> Self {
+        metrics.record_request_start(&route);
+        Self {
+            metrics,
+            span: Span::current(),
+            method,
+            route,
+            request_id,
+            start: Instant::now(),
+            status: None,
+            streaming: false,
+            buffer: Vec::new(),
+            truncated: false,
+            first_event: true,
+            model: None,
+            usage: None,
+            reservation: None,
+            variant: None,
+        }
+    }
+
+    /// Buffers the chunk, parsing each complete line for the streamed responses (i.e. Server-Sent
+    /// Events), or the whole body for the rest once dropped
+    fn observe(&mut self, chunk: &Bytes) {
+        if self.streaming {
+            self.buffer.extend_from_slice(chunk);
+            while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+                self.observe_event(&line);
+            }
+        } else if !self.truncated {
+            if self.buffer.len() + chunk.len() > MAX_USAGE_BODY_BYTES {
+                self.truncated = true;
+                self.buffer = Vec::new();
+            } else {
+                self.buffer.extend_from_slice(chunk);
+            }
+        }
+    }
+
+    fn observe_event(&mut self, line: &[u8]) {
+        let Some(data) = line.strip_prefix(b"data:") else {
+            return;
+        };
+        let Ok(summary) = serde_json::from_slice::<ResponseSummary>(data.trim_ascii()) else {
+            // e.g. the `[DONE]` event that terminates the stream
+            return;
+        };
+
+        if summary.model.is_some() {
+            self.model = summary.model;
+        }
+        if summary.usage.is_some() {
+            self.usage = summary.usage;
+        }
+
+        if self.first_event {
+            self.first_event = false;
+            self.metrics.record_time_to_first_token(
+                &self.route,
+                self.model.as_deref().unwrap_or_default(),
+                self.start.elapsed(),
+            );
+        }
+    }
+}
+
+impl Drop for ResponseObserver {
+    fn drop(&mut self) {
+        if !self.streaming && !self.truncated && !self.buffer.is_empty() {
+            let summary =
+                serde_json::from_slice::<ResponseSummary>(&self.buffer).unwrap_or_default();
+            self.model = summary.model;
+            self.usage = summary.usage;
+        }
+
+        // The tokens reserved by the rate limiter are corrected with the actual usage, if reported
+        if let (Some(reservation), Some(usage)) = (self.reservation.take(), &self.usage) {
+            reservation.settle(usage.total_tokens);
+        }
+
+        let latency = self.start.elapsed();
+        let status = self
+            .status
+            .map(|status| status.as_str().to_string())
+            .unwrap_or_else(|| CLIENT_CLOSED_REQUEST.to_string());
+
+        self.metrics.record_request(
+            &self.route,
+            &status,
+            self.model.as_deref().unwrap_or_default(),
+            latency,
+            self.usage.as_ref(),
+        );
+        if let Some(variant) = &self.variant {
+            self.metrics
+                .record_variant_request(&variant.split, &variant.name, &status, latency);
+        }
+
+        if let Some(model) = &self.model {
+            self.span.record("gen_ai.response.model", model);
+        }
+        if let Some(usage) = &self.usage {
+            self.span
+                .record("gen_ai.usage.input_tokens", usage.prompt_tokens)
+                .record("gen_ai.usage.output_tokens", usage.completion_tokens);
+        }
+
+        self.span.in_scope(|| {
+            tracing::info!(
+                request_id = self.request_id.as_ref().map(|request_id| request_id.id.as_str()),
+                client_request_id = self
+                    .request_id
+                    .as_ref()
+                    .and_then(|request_id| request_id.client_id.as_deref()),
+                method = %self.method,
+                route = self.route,
+                model = self.model,
+                variant = self.variant.as_ref().map(|variant| variant.name.as_str()),
+                status,
+                latency_ms = latency.as_secs_f64() * 1000.0,
+                prompt_tokens = self.usage.as_ref().map(|usage| usage.prompt_tokens),
+                completion_tokens = self.usage.as_ref().map(|usage| usage.completion_tokens),
+                "Request completed"
+            )
+        });
+    }
+}
+
+/// This function writes the access log of each request, wrapping the response body so that the
+/// status, the latency, the model and the token usage reported by the upstream are logged once the
+/// body has been fully sent, and recorded on both the metrics and the request span too; for the
+/// streamed responses, it also records the time to the first event (i.e. the first token).
+pub async fn access_log_middleware(
+    State(state): State<ProxyState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let request_id = request.extensions().get::<RequestId>().cloned();
+
+    // Created before running the request, so that the requests dropped before the response is
+    // produced are logged too
+    let mut observer = ResponseObserver::new(
+        state.metrics.clone(),
+        request.method().clone(),
+        route,
+        request_id,
+    );
+
+    let mut response = next.run(request).await;
+    observer.reservation = response.extensions_mut().remove::<TokenReservation>();
+    observer.variant = response.extensions_mut().remove::<Variant>();
+    observer.status = Some(response.status());
+    observer.streaming = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            observer.observe(chunk);
+        }
+    });
+
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe_streamed_usage() {
+        let metrics = Metrics::default();
+        let mut observer = ResponseObserver::new(
+            metrics.clone(),
+            Method::POST,
+            "/chat/completions".to_string(),
+            None,
+        );
+        observer.status = Some(StatusCode::OK);
+        observer.streaming = true;
+
+        // Events split across chunks, with the usage sent in the last event before `[DONE]`
+        observer.observe(&Bytes::from_static(
+            b"data: {\"model\":\"phi\",\"choices\":[]}\n\ndata: {\"model\":\"phi\",\"ch",
+        ));
+        observer.observe(&Bytes::from_static(
+            b"oices\":[],\"usage\":{\"prompt_tokens\":10,\"completion_tokens\":5,\"total_tokens\":15}}\n\ndata: [DONE]\n\n",
+        ));
+        assert_eq!(observer.model.as_deref(), Some("phi"));
+        assert_eq!(
+            observer.usage.as_ref().map(|usage| usage.total_tokens),
+            Some(15)
+        );
+        drop(observer);
+
+        let encoded = metrics.encode();
+        for line in [
+            r#"azure_openai_proxy_prompt_tokens_total{model="phi",route="/chat/completions"} 10"#,
+            r#"azure_openai_proxy_completion_tokens_total{model="phi",route="/chat/completions"} 5"#,
+            r#"azure_openai_proxy_time_to_first_token_seconds_count{model="phi",route="/chat/completions"} 1"#,
+            r#"azure_openai_proxy_requests_total{model="phi",route="/chat/completions",status="200"} 1"#,
+            r#"azure_openai_proxy_in_flight_requests{route="/chat/completions"} 0"#,
+        ] {
+            assert!(encoded.contains(line), "missing `{line}` in:\n{encoded}");
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{errors::AzureError, proxy::ProxyState, utils::stable_hash};
+use axum::{
+    extract::{Request, State},
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use subtle::{Choice, ConstantTimeEq};
+
+/// This function checks that the request is authenticated with any of the configured API keys,
+/// provided either via the `api-key` header or via the `Authorization: Bearer` header, as both are
+/// supported by the Azure AI Model Inference API. If no API keys are configured, then the
+/// authentication is disabled and all the requests are let through.
+pub async fn auth_middleware(
+    State(state): State<ProxyState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AzureError> {
+    let config = state.config.load_full();
+    let api_keys = &config.auth.api_keys;
+    if api_keys.is_empty() {
+        return Ok(next.run(request).await);
+    }
+
+    match authenticated_api_key(api_keys, request.headers()) {
+        Some(_) => Ok(next.run(request).await),
+        None => Err(AzureError::Unauthorized),
+    }
+}
+
+/// Returns the API key provided via either the `api-key` or the `Authorization: Bearer` headers
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("api-key")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+}
+
+/// Returns the API key provided via the headers only if it's any of the given API keys, compared
+/// in constant time so that the keys can't be guessed from how long the comparison takes
+pub fn authenticated_api_key<'a>(api_keys: &[String], headers: &'a HeaderMap) -> Option<&'a str> {
+    let api_key = api_key(headers)?;
+    // All the keys are compared, so that the time taken doesn't tell which one matched
+    let matched = api_keys.iter().fold(Choice::from(0), |matched, key| {
+        matched | key.as_bytes().ct_eq(api_key.as_bytes())
+    });
+    bool::from(matched).then_some(api_key)
+}
+
+/// Returns the hash of the credentials forwarded to the upstream via either the `api-key` or the
+/// `Authorization` headers (if any), so that what the upstream served to a caller is only shared
+/// with the callers forwarding the same credentials
+pub fn credentials_hash(headers: &HeaderMap) -> u64 {
+    let credentials = ["api-key", AUTHORIZATION.as_str()].map(|name| {
+        headers
+            .get(name)
+            .map(HeaderValue::as_bytes)
+            .unwrap_or_default()
+    });
+    stable_hash(&credentials.join(&b'\n'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authenticated_api_key() {
+        let api_keys = ["sk-first".to_string(), "sk-second".to_string()];
+        let headers = |name: &str, value: &str| {
+            HeaderMap::from_iter([(name.parse().unwrap(), value.parse().unwrap())])
+        };
+
+        let second = headers("api-key", "sk-second");
+        assert_eq!(authenticated_api_key(&api_keys, &second), Some("sk-second"));
+        let bearer = headers("authorization", "Bearer sk-first");
+        assert_eq!(authenticated_api_key(&api_keys, &bearer), Some("sk-first"));
+        // Neither the unknown keys nor the prefixes of the configured ones are authenticated
+        for api_key in ["sk-third", "sk-", ""] {
+            let unknown = headers("api-key", api_key);
+            assert_eq!(authenticated_api_key(&api_keys, &unknown), None);
+        }
+        assert_eq!(authenticated_api_key(&[], &second), None);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::proxy::ProxyState;
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http_body_util::Limited;
+
+/// Default maximum size of the request body, same as the Axum default
+const DEFAULT_MAX_REQUEST_BODY_BYTES: usize = 2 * 1024 * 1024;
+
+/// This function limits the size of the request body to the `limits.max_request_body_bytes` of the
+/// current configuration, so that the limit can be changed on reload; the extractors reading
+/// the body will reject it with a 413 status if the limit is exceeded.
+pub async fn body_limit_middleware(
+    State(state): State<ProxyState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limit = state
+        .config
+        .load()
+        .limits
+        .max_request_body_bytes
+        .unwrap_or(DEFAULT_MAX_REQUEST_BODY_BYTES);
+
+    let (parts, body) = request.into_parts();
+    let request = Request::from_parts(parts, Body::new(Limited::new(body, limit)));
+
+    next.run(request).await
+}
@@ -0,0 +1,7 @@
+pub mod access_log;
+pub mod auth;
+pub mod limits;
+pub mod rate_limit;
+pub mod readiness;
+pub mod request_id;
+pub mod trace;
@@ -0,0 +1,181 @@
+use crate::{
+    config::RateLimitKey,
+    errors::AzureError,
+    middlewares::auth::authenticated_api_key,
+    proxy::{ClientAddr, ProxyState},
+    rate_limit::{Decision, Limits, RateLimitStatus},
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{ConnectInfo, FromRequest, Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+
+/// Rough number of bytes per token, used to estimate the prompt tokens from the request size
+pub const BYTES_PER_TOKEN: u64 = 4;
+
+/// The fields read from the request body to both key and estimate the tokens of the request
+#[derive(Deserialize, Debug, Default)]
+struct RequestSummary {
+    model: Option<String>,
+    max_tokens: Option<u64>,
+}
+
+/// Returns the client IP, either from the first address in the `x-forwarded-for` header if trusted,
+/// or from the connection otherwise (not available over an Unix domain socket)
+fn client_ip(request: &Request, trust_forwarded_for: bool) -> Option<String> {
+    let forwarded_for = trust_forwarded_for
+        .then(|| request.headers().get("x-forwarded-for"))
+        .flatten()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+
+    forwarded_for.or_else(|| {
+        request
+            .extensions()
+            .get::<ConnectInfo<ClientAddr>>()
+            .and_then(|ConnectInfo(ClientAddr(addr))| addr.map(|addr| addr.to_string()))
+    })
+}
+
+/// Adds the `x-ratelimit-remaining-requests` and `x-ratelimit-remaining-tokens` headers, for the
+/// limits that are enabled
+fn insert_status_headers(headers: &mut HeaderMap, status: RateLimitStatus) {
+    for (name, remaining) in [
+        ("x-ratelimit-remaining-requests", status.remaining_requests),
+        ("x-ratelimit-remaining-tokens", status.remaining_tokens),
+    ] {
+        if let Some(remaining) = remaining {
+            headers.insert(name, HeaderValue::from(remaining));
+        }
+    }
+}
+
+/// This function applies the configured requests and tokens per minute to each API key, model or
+/// client IP, rejecting the requests over the limits with a 429 status and the `Retry-After`
+/// header. The tokens are estimated from both the request size and `max_tokens`, and the estimate
+/// is then corrected with the `usage` reported by the upstream once the response has been sent.
+pub async fn rate_limit_middleware(
+    State(state): State<ProxyState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = state.config.load_full();
+    let limits = Limits {
+        requests_per_minute: config.rate_limit.requests_per_minute,
+        tokens_per_minute: config.rate_limit.tokens_per_minute,
+    };
+    if limits.requests_per_minute.is_none() && limits.tokens_per_minute.is_none() {
+        return next.run(request).await;
+    }
+
+    let client_ip = client_ip(&request, config.rate_limit.trust_forwarded_for);
+    // Only the authenticated API keys are trusted as keys, as the client could otherwise get a
+    // fresh bucket on every request by sending another key
+    let api_key =
+        authenticated_api_key(&config.auth.api_keys, request.headers()).map(str::to_string);
+
+    // The body is buffered to read the model and `max_tokens` from, and then forwarded as is
+    let (parts, body) = request.into_parts();
+    let bytes = match Bytes::from_request(Request::new(body), &()).await {
+        Ok(bytes) => bytes,
+        Err(rejection) => return rejection.into_response(),
+    };
+    let summary = serde_json::from_slice::<RequestSummary>(&bytes).unwrap_or_default();
+    let estimated_tokens = bytes.len() as u64 / BYTES_PER_TOKEN + summary.max_tokens.unwrap_or(0);
+
+    let key = match config.rate_limit.key {
+        RateLimitKey::ApiKey => api_key.or(client_ip),
+        RateLimitKey::Model => summary.model,
+        RateLimitKey::ClientIp => client_ip,
+    }
+    .unwrap_or_default();
+
+    match state.rate_limiter.acquire(&key, estimated_tokens, limits) {
+        Decision::Allowed(status, reservation) => {
+            let mut response = next
+                .run(Request::from_parts(parts, Body::from(bytes)))
+                .await;
+            insert_status_headers(response.headers_mut(), status);
+            if let Some(reservation) = reservation {
+                response.extensions_mut().insert(reservation);
+            }
+            response
+        }
+        Decision::Throttled(status, wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let mut response = AzureError::TooManyRequests(
+                format!("Rate limit exceeded, please retry after {retry_after} seconds."),
+                retry_after,
+            )
+            .into_response();
+            insert_status_headers(response.headers_mut(), status);
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, middleware, routing::post, Router};
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn test_keyed_by_authenticated_api_key() {
+        let app = |auth: &str| {
+            let state = ProxyState::from_toml(&format!(
+                r#"
+                [upstreams.vllm]
+                host = "127.0.0.1"
+                port = 8080
+
+                [routes.chat-completions]
+                upstream = "vllm"
+
+                [rate_limit]
+                key = "api-key"
+                requests_per_minute = 1
+
+                {auth}
+                "#
+            ));
+            Router::new()
+                .route("/chat/completions", post(async || "ok"))
+                .route_layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    rate_limit_middleware,
+                ))
+                .with_state(state)
+        };
+        let send = async |app: &mut Router, api_key: &str| {
+            let request = Request::post("/chat/completions")
+                .header("api-key", api_key)
+                .body(Body::empty())
+                .unwrap();
+            app.call(request).await.unwrap().status()
+        };
+
+        // Without the authentication, rotating the key doesn't reset the limit
+        let mut open = app("");
+        assert_eq!(send(&mut open, "sk-first").await, StatusCode::OK);
+        assert_eq!(
+            send(&mut open, "sk-second").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // Whereas each of the authenticated keys has its own limit
+        let mut authenticated = app("[auth]\napi_keys = [\"sk-first\", \"sk-second\"]");
+        assert_eq!(send(&mut authenticated, "sk-first").await, StatusCode::OK);
+        assert_eq!(send(&mut authenticated, "sk-second").await, StatusCode::OK);
+        assert_eq!(
+            send(&mut authenticated, "sk-first").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
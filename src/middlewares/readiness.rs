@@ -0,0 +1,70 @@
+use crate::{errors::AzureError, proxy::ProxyState};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+/// Seconds the client is asked to wait before retrying, while the upstreams are not ready yet
+const RETRY_AFTER_SECS: u64 = 10;
+
+/// This function rejects the requests with a 503 status and the `Retry-After` header until the
+/// startup gate is open i.e. all the upstreams are ready, rather than forwarding those to an
+/// upstream that is still starting (e.g. loading the model weights) and failing with a 502.
+pub async fn readiness_middleware(
+    State(state): State<ProxyState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AzureError> {
+    if !state.startup.is_open() {
+        return Err(AzureError::ServiceUnavailable(
+            "The upstream is starting and not ready to serve requests yet.".to_string(),
+            RETRY_AFTER_SECS,
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::RETRY_AFTER, StatusCode},
+        middleware,
+        routing::post,
+        Router,
+    };
+    use serde_json::Value;
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn test_rejects_until_ready() {
+        let state = ProxyState::from_toml("");
+        let mut app = Router::new()
+            .route("/chat/completions", post(|| async { "OK" }))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                readiness_middleware,
+            ))
+            .with_state(state.clone());
+        let request = || {
+            Request::post("/chat/completions")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Rejected with a 503 rather than a 502, asking the client to retry later
+        let res = app.call(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], RETRY_AFTER_SECS.to_string());
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["code"], "ServiceUnavailable");
+
+        state.startup.open();
+        let res = app.call(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,131 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use uuid::Uuid;
+
+/// Header with the request ID provided by the Azure clients, which is echoed back
+pub const CLIENT_REQUEST_ID: HeaderName = HeaderName::from_static("x-ms-client-request-id");
+
+/// Header with the request ID generated by the proxy, as returned by Azure API Management
+pub const SERVER_REQUEST_ID: HeaderName = HeaderName::from_static("apim-request-id");
+
+/// Header the request ID generated by the proxy is forwarded to the upstream with
+pub const UPSTREAM_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    /// The ID generated by the proxy for the request being served, so that it can be included
+    /// within the error responses
+    pub static REQUEST_ID: String;
+}
+
+/// The IDs of the request, stored within the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId {
+    /// The ID generated by the proxy
+    pub id: String,
+
+    /// The ID provided by the client via `x-ms-client-request-id`, if any
+    pub client_id: Option<String>,
+}
+
+/// This function generates an ID for each request, which is forwarded to the upstream via the
+/// `x-request-id` header and returned via the `apim-request-id` header, along with the
+/// `x-ms-client-request-id` provided by the client (if any), so that the requests can be
+/// correlated end to end across the client, the proxy and the upstream logs.
+pub async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let id = Uuid::new_v4().to_string();
+    let client_id = request.headers().get(CLIENT_REQUEST_ID).cloned();
+
+    let id_value = HeaderValue::from_str(&id).expect("UUID should be a valid header value");
+    request
+        .headers_mut()
+        .insert(UPSTREAM_REQUEST_ID, id_value.clone());
+    request.extensions_mut().insert(RequestId {
+        id: id.clone(),
+        client_id: client_id
+            .as_ref()
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string()),
+    });
+
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+
+    response.headers_mut().insert(SERVER_REQUEST_ID, id_value);
+    if let Some(client_id) = client_id {
+        response.headers_mut().insert(CLIENT_REQUEST_ID, client_id);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::AzureError;
+    use axum::{
+        body::{to_bytes, Body},
+        http::HeaderMap,
+        middleware,
+        routing::get,
+        Router,
+    };
+    use serde_json::Value;
+    use tower_service::Service;
+
+    #[tokio::test]
+    async fn test_request_id_precedence() {
+        // The upstream reports the request ID it received, and sets request IDs of its own
+        let upstream = |headers: HeaderMap| async move {
+            let id = headers[UPSTREAM_REQUEST_ID].to_str().unwrap().to_string();
+            (
+                [
+                    (SERVER_REQUEST_ID, "upstream-id"),
+                    (CLIENT_REQUEST_ID, "other-id"),
+                ],
+                id,
+            )
+        };
+        let mut app = Router::new()
+            .route("/", get(upstream))
+            .route("/error", get(|| async { AzureError::Unauthorized }))
+            .layer(middleware::from_fn(request_id_middleware));
+
+        let request = |uri| {
+            Request::get(uri)
+                .header(UPSTREAM_REQUEST_ID, "client-upstream-id")
+                .header(CLIENT_REQUEST_ID, "client-id")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app.call(request("/")).await.unwrap();
+        let id = res.headers()[SERVER_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_ne!(id, "upstream-id");
+        assert_eq!(res.headers()[CLIENT_REQUEST_ID], "client-id");
+        // The generated ID is forwarded instead of the one provided by the client
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, id);
+
+        // The error responses include the generated ID too
+        let res = app.call(request("/error")).await.unwrap();
+        let id = res.headers()[SERVER_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["request_id"], id);
+
+        // Without a client ID, none is returned
+        let res = app
+            .call(Request::get("/error").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert!(res.headers().contains_key(SERVER_REQUEST_ID));
+        assert!(!res.headers().contains_key(CLIENT_REQUEST_ID));
+    }
+}
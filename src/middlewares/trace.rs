@@ -0,0 +1,140 @@
+use crate::middlewares::request_id::RequestId;
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::global;
+use opentelemetry_http::HeaderExtractor;
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// This function creates the span of each proxied request, continuing the trace from the incoming
+/// `traceparent` and `tracestate` headers (if any), so that the spans of the proxy are linked to
+/// both the ones of the client and the ones of the upstream. The request IDs are recorded on the
+/// span, so that those are included within every log line of the request. The span is closed once
+/// the response body has been fully sent, as it's held by the access log middleware until then,
+/// which records the model and the token usage reported by the upstream on it.
+pub async fn trace_middleware(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let request_id = request.extensions().get::<RequestId>().cloned();
+
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", request.method(), route),
+        otel.kind = "server",
+        http.request.method = %request.method(),
+        http.route = route,
+        request_id = request_id.as_ref().map(|request_id| request_id.id.as_str()),
+        client_request_id = request_id.as_ref().and_then(|request_id| request_id.client_id.as_deref()),
+        http.response.status_code = Empty,
+        az.api_version = Empty,
+        gen_ai.request.model = Empty,
+        gen_ai.response.model = Empty,
+        gen_ai.usage.input_tokens = Empty,
+        gen_ai.usage.output_tokens = Empty,
+    );
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    // Only fails if the span is disabled, in which case there's nothing to propagate
+    let _ = span.set_parent(parent);
+
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proxy::ProxyState, upstream::send_request, utils::append_path_to_uri, utils::serve_locally,
+        UpstreamType,
+    };
+    use axum::{
+        body::{to_bytes, Body, Bytes},
+        http::HeaderMap,
+        middleware,
+        routing::post,
+        Router,
+    };
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider};
+    use tower_service::Service;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_propagates_trace_context() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        // The test runs on a single thread, so the subscriber applies to both the proxy and the
+        // fake upstream
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        // The upstream echoes the trace context it received
+        let uri = serve_locally(Router::new().route(
+            "/v1/chat/completions",
+            post(|headers: HeaderMap| async move {
+                headers["traceparent"].to_str().unwrap().to_string()
+            }),
+        ))
+        .await;
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.vllm]
+            host = "127.0.0.1"
+            port = {}
+
+            [routes.chat-completions]
+            upstream = "vllm"
+            "#,
+            uri.port_u16().unwrap()
+        ));
+        let proxy = |request: Request| async move {
+            let config = state.config.load_full();
+            let route = UpstreamType::ChatCompletions;
+            let upstream = state
+                .resolve(&config, &route, None, request.headers(), None)
+                .unwrap();
+            let uri = append_path_to_uri(upstream.uri.clone(), "/v1/chat/completions");
+            let req = Request::post(uri).body(Bytes::new()).unwrap();
+            send_request(&state, &upstream, req).await.unwrap()
+        };
+        let mut app = Router::new()
+            .route("/chat/completions", post(proxy))
+            .route_layer(middleware::from_fn(trace_middleware));
+
+        let res = app
+            .call(
+                Request::post("/chat/completions")
+                    .header(
+                        "traceparent",
+                        "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let traceparent = String::from_utf8(body.to_vec()).unwrap();
+
+        // Same trace as the client, but with the upstream span of the proxy as the parent
+        let [version, trace_id, parent_id, flags] = traceparent.split('-').collect::<Vec<_>>()[..]
+        else {
+            panic!("invalid traceparent '{traceparent}'");
+        };
+        assert_eq!(
+            (version, trace_id, flags),
+            ("00", "0af7651916cd43dd8448eb211c80319c", "01")
+        );
+        assert_ne!(parent_id, "b7ad6b7169203331");
+    }
+}
@@ -1,91 +1,403 @@
 use crate::{
+    cache::{run_disk_sweeps, ResponseCache},
+    circuit_breaker::CircuitBreakers,
+    concurrency::ConcurrencyLimiter,
+    config::{Config, ResolvedUpstream},
+    connector::UpstreamConnector,
+    embeddings_batcher::EmbeddingsBatcher,
+    embeddings_cache::EmbeddingsCache,
+    engine_metrics::run_metrics_scrapes,
+    errors::ConfigError,
     handlers::{
-        chat_completions::chat_completions_handler, embeddings::embeddings_handler,
-        health::health_handler, info::info_handler,
+        admin::reload_handler,
+        chat_completions::chat_completions_handler,
+        embeddings::embeddings_handler,
+        health::{health_handler, liveness_handler, readiness_handler},
+        info::info_handler,
+        metrics::metrics_handler,
     },
-    UpstreamType,
+    health_check::{run_health_checks, wait_for_upstreams, HealthRegistry, StartupGate},
+    load_balancer::LoadBalancer,
+    metrics::Metrics,
+    middlewares::{
+        access_log::access_log_middleware, auth::auth_middleware, limits::body_limit_middleware,
+        rate_limit::rate_limit_middleware, readiness::readiness_middleware,
+        request_id::request_id_middleware, trace::trace_middleware,
+    },
+    rate_limit::RateLimiter,
+    shadow::ShadowLimiter,
+    telemetry::init_tracing,
+    traffic_split::choose_variant,
+    Cli, UpstreamType,
 };
+use arc_swap::ArcSwap;
 use axum::{
     body::Body,
-    http::Uri,
+    extract::{connect_info::Connected, DefaultBodyLimit},
+    http::HeaderMap,
+    middleware,
     routing::{get, post},
+    serve::{IncomingStream, Listener},
     Router,
 };
-use hyper_util::{
-    client::legacy::{connect::HttpConnector, Client},
-    rt::TokioExecutor,
-};
-use tokio::signal;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use listenfd::ListenFd;
+use std::{fmt::Debug, net::IpAddr, sync::Arc};
+#[cfg(unix)]
+use std::{os::unix::fs::FileTypeExt, path::Path};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, signal};
 
-/// Custom type for the Hyper HTTP Client that will be used / shared as the application state
-pub type HttpClient = Client<HttpConnector, Body>;
+/// Custom type for the Hyper HTTP Client that will be used / shared as the application state, which
+/// can connect to the upstream either over TCP or over an Unix domain socket
+pub type HttpClient = Client<UpstreamConnector, Body>;
 
 /// Custom API state to be shared across all the proxy endpoints
 #[derive(Debug, Clone)]
 pub struct ProxyState {
     pub client: HttpClient,
-    pub uri: Uri,
-    pub upstream_type: UpstreamType,
+    /// The current configuration, which is atomically swapped on reload, so that the in-flight
+    /// requests keep the configuration snapshot they started with
+    pub config: Arc<ArcSwap<Config>>,
+    /// The CLI arguments and environment variables, re-applied on top of the configuration file on
+    /// every reload
+    pub cli: Arc<Cli>,
+    /// The health of each upstream, as observed by the periodic health checks
+    pub health: HealthRegistry,
+    /// The gate that opens once all the upstreams are ready to serve requests
+    pub startup: StartupGate,
+    /// The Prometheus metrics of the proxy
+    pub metrics: Metrics,
+    /// The request and token buckets of each rate limit key
+    pub rate_limiter: RateLimiter,
+    /// The requests in flight to and waiting for each upstream with a concurrency limit
+    pub concurrency: ConcurrencyLimiter,
+    /// The circuit breaker of each upstream
+    pub circuit_breakers: CircuitBreakers,
+    /// The load balancer across the upstreams of each pool
+    pub load_balancer: LoadBalancer,
+    /// The cache of the responses to the deterministic requests
+    pub cache: ResponseCache,
+    /// The cache of the embeddings of each input
+    pub embeddings_cache: EmbeddingsCache,
+    /// The open batches of the concurrent embeddings requests
+    pub embeddings_batcher: EmbeddingsBatcher,
+    /// The requests in flight to the shadow upstreams
+    pub shadow_limiter: ShadowLimiter,
 }
 
-/// Starts the Axum server i.e. the proxy
-pub async fn start_server(
-    host: Option<&str>,
-    port: Option<&u16>,
-    upstream_host: &str,
-    upstream_port: Option<&u16>,
-    upstream_type: &UpstreamType,
-) {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| format!("{}=info", env!("CARGO_CRATE_NAME")).into()),
+impl ProxyState {
+    /// Builds the state shared across the proxy endpoints, starting from the given configuration
+    pub fn new(config: Config, cli: Cli) -> Self {
+        Self {
+            client: Client::builder(TokioExecutor::new()).build(UpstreamConnector::new()),
+            config: Arc::new(ArcSwap::from_pointee(config)),
+            cli: Arc::new(cli),
+            health: HealthRegistry::default(),
+            startup: StartupGate::default(),
+            metrics: Metrics::default(),
+            rate_limiter: RateLimiter::default(),
+            concurrency: ConcurrencyLimiter::default(),
+            circuit_breakers: CircuitBreakers::default(),
+            load_balancer: LoadBalancer::default(),
+            cache: ResponseCache::default(),
+            embeddings_cache: EmbeddingsCache::default(),
+            embeddings_batcher: EmbeddingsBatcher::default(),
+            shadow_limiter: ShadowLimiter::default(),
+        }
+    }
+
+    /// Builds the state from the configuration in TOML, e.g. routing to an upstream faked via
+    /// `serve_locally`
+    #[cfg(test)]
+    pub fn from_toml(config: &str) -> Self {
+        Self::new(toml::from_str(config).unwrap(), Cli::default())
+    }
+
+    /// Re-reads the configuration file and re-applies the CLI arguments and environment variables,
+    /// swapping the current configuration only if the new one is valid
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = Config::load(&self.cli).inspect_err(|e| {
+            tracing::error!("Configuration reload rejected, keeping the current one: {e}")
+        })?;
+
+        let current = self.config.load();
+        if config.server.host != current.server.host || config.server.port != current.server.port {
+            tracing::warn!("Changes to `server` require a restart to take effect");
+        }
+        if config.metrics.port != current.metrics.port {
+            tracing::warn!("Changes to `metrics` require a restart to take effect");
+        }
+        if config.logging.level != current.logging.level
+            || config.logging.format != current.logging.format
+        {
+            tracing::warn!("Changes to `logging` require a restart to take effect");
+        }
+        if config.tracing != current.tracing {
+            tracing::warn!("Changes to `tracing` require a restart to take effect");
+        }
+
+        self.config.store(Arc::new(config));
+        tracing::info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Resolves the upstream serving the route and model within the configuration snapshot,
+    /// picking the variant of a traffic split for the request headers, and balancing the requests
+    /// across the upstreams of a pool, optionally on the prefix of the request with a length given
+    /// by the pool
+    pub fn resolve(
+        &self,
+        config: &Config,
+        route: &UpstreamType,
+        model: Option<&str>,
+        headers: &HeaderMap,
+        prefix: Option<&dyn Fn(usize) -> String>,
+    ) -> Option<ResolvedUpstream> {
+        config.resolve(
+            route,
+            model,
+            |name, pool| {
+                self.load_balancer
+                    .pick(name, pool, prefix, &self.health, &self.circuit_breakers)
+            },
+            |_, split| choose_variant(split, headers),
         )
-        .compact()
-        .init();
+    }
 
-    let client: HttpClient = Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+    /// Resolves the shadow upstream of the upstream (if any) within the configuration snapshot
+    pub fn resolve_shadow(
+        &self,
+        config: &Config,
+        upstream: &ResolvedUpstream,
+        headers: &HeaderMap,
+    ) -> Option<ResolvedUpstream> {
+        config.resolve_shadow(
+            upstream,
+            |name, pool| {
+                self.load_balancer
+                    .pick(name, pool, None, &self.health, &self.circuit_breakers)
+            },
+            |_, split| choose_variant(split, headers),
+        )
+    }
 
-    let uri: Uri = {
-        let port_str = upstream_port.map(|p| format!(":{p}")).unwrap_or_default();
-        let full_uri = format!("{}{}", upstream_host, port_str);
-        Uri::try_from(full_uri).unwrap()
-    };
+    /// Resolves the fallback of the upstream (if any) within the configuration snapshot
+    pub fn resolve_fallback(
+        &self,
+        config: &Config,
+        upstream: &ResolvedUpstream,
+        headers: &HeaderMap,
+    ) -> Option<ResolvedUpstream> {
+        config.resolve_fallback(
+            upstream,
+            |name, pool| {
+                self.load_balancer
+                    .pick(name, pool, None, &self.health, &self.circuit_breakers)
+            },
+            |_, split| choose_variant(split, headers),
+        )
+    }
+}
 
-    let state = ProxyState {
-        client,
-        uri,
-        upstream_type: upstream_type.clone(),
-    };
+/// Starts the Axum server i.e. the proxy
+pub async fn start_server(config: Config, cli: Cli) {
+    let tracer_provider = init_tracing(&config);
+
+    let state = ProxyState::new(config, cli);
 
-    // TODO: add periodic health checks to the underlying service to terminate the proxy if the
-    // underlying service is down
+    // Both routes are always registered, as those can be enabled or disabled on reload, and the
+    // requests to a route without an upstream configured are rejected by the handler
     let app = Router::new()
+        .route("/chat/completions", post(chat_completions_handler))
+        .route("/embeddings", post(embeddings_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            readiness_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit_middleware,
+        ))
+        .route("/info", get(info_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            access_log_middleware,
+        ))
+        .route_layer(middleware::from_fn(trace_middleware))
         .route("/health", get(health_handler))
-        .route("/info", get(info_handler));
+        .route("/liveness", get(liveness_handler))
+        .route("/readiness", get(readiness_handler))
+        // The body limit is applied by the middleware instead, as it can be changed on reload
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit_middleware,
+        ))
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn(request_id_middleware));
+
+    tokio::spawn(wait_for_upstreams(state.clone()));
+    tokio::spawn(run_health_checks(state.clone()));
+    tokio::spawn(run_metrics_scrapes(state.clone()));
+    tokio::spawn(run_disk_sweeps(state.clone()));
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone()));
 
-    let app = match upstream_type {
-        UpstreamType::ChatCompletions => {
-            app.route("/chat/completions", post(chat_completions_handler))
+    let (host, port, metrics_port) = {
+        let config = state.config.load();
+        (
+            config.server.host.clone(),
+            config.server.port,
+            config.metrics.port,
+        )
+    };
+
+    // The metrics are either served by a separate listener (e.g. so that those are not exposed
+    // publicly along with the proxy), or by the proxy itself otherwise; whereas the configuration
+    // can only be reloaded via the separate listener (or the SIGHUP signal), as the proxy port may
+    // be reachable by anyone when the authentication is disabled
+    let app = match metrics_port {
+        Some(metrics_port) => {
+            let metrics_host = if host.starts_with("unix://") {
+                "0.0.0.0"
+            } else {
+                &host
+            };
+            let listener = TcpListener::bind(format!("{}:{}", metrics_host, metrics_port))
+                .await
+                .unwrap();
+            let metrics_app = Router::new()
+                .route("/metrics", get(metrics_handler))
+                .route("/admin/reload", post(reload_handler))
+                .with_state(state.clone());
+            tokio::spawn(serve(listener, metrics_app));
+            app
         }
-        UpstreamType::Embeddings => app.route("/embeddings", post(embeddings_handler)),
+        None => app.route("/metrics", get(metrics_handler)),
     };
 
+    let listener = bind_listener(&host, &port, ListenFd::from_env()).await;
     let app = app.with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(format!(
-        "{}:{}",
-        host.unwrap_or("0.0.0.0"),
-        port.unwrap_or(&80u16),
-    ))
+    match listener {
+        ProxyListener::Tcp(listener) => serve(listener, app).await,
+        #[cfg(unix)]
+        ProxyListener::Unix(listener) => serve(listener, app).await,
+    }
+
+    // Flushes the pending spans before exiting, within a blocking task as the export is blocking
+    if let Some(tracer_provider) = tracer_provider {
+        let _ = tokio::task::spawn_blocking(move || tracer_provider.shutdown()).await;
+    }
+}
+
+/// Listener the proxy accepts the incoming connections from
+enum ProxyListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// The address of the client connected to the proxy, which is only available over TCP
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        Self(Some(stream.remote_addr().ip()))
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for ClientAddr {
+    fn connect_info(_stream: IncomingStream<'_, UnixListener>) -> Self {
+        Self(None)
+    }
+}
+
+/// Binds the listener for the proxy, which is either the socket inherited via systemd socket
+/// activation (i.e. `LISTEN_FDS`) if any, in which case both the host and port are ignored; an
+/// Unix domain socket if the host is provided with the `unix://` scheme e.g.
+/// `unix:///run/azure-openai-proxy.sock`; or a TCP socket bound to the host and port otherwise.
+async fn bind_listener(host: &str, port: &u16, mut listenfd: ListenFd) -> ProxyListener {
+    if listenfd.len() > 0 {
+        tracing::info!("Using the socket inherited via systemd socket activation");
+        // The inherited file descriptor can either be a TCP or an Unix domain socket, so if it's
+        // not a TCP socket, then it's handled as an Unix domain socket instead
+        if let Ok(Some(listener)) = listenfd.take_tcp_listener(0) {
+            listener.set_nonblocking(true).unwrap();
+            return ProxyListener::Tcp(TcpListener::from_std(listener).unwrap());
+        }
+
+        #[cfg(unix)]
+        {
+            let listener = listenfd.take_unix_listener(0).unwrap().expect(
+                "Inherited file descriptor should be either a TCP or an Unix domain socket",
+            );
+            listener.set_nonblocking(true).unwrap();
+            return ProxyListener::Unix(UnixListener::from_std(listener).unwrap());
+        }
+        #[cfg(not(unix))]
+        panic!("Inherited file descriptor should be a TCP socket");
+    }
+
+    match host.strip_prefix("unix://") {
+        #[cfg(unix)]
+        Some(socket_path) => {
+            // Removes the stale socket (if any) from a previous run, as otherwise the bind fails
+            let socket_path = Path::new(socket_path);
+            if socket_path
+                .metadata()
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(socket_path).unwrap();
+            }
+            ProxyListener::Unix(UnixListener::bind(socket_path).unwrap())
+        }
+        #[cfg(not(unix))]
+        Some(_) => panic!("Unix domain sockets are only supported on Unix platforms"),
+        None => ProxyListener::Tcp(
+            TcpListener::bind(format!("{}:{}", host, port))
+                .await
+                .unwrap(),
+        ),
+    }
+}
+
+/// Serves the Axum application over the provided listener until the shutdown signal is received
+async fn serve<L>(listener: L, app: Router)
+where
+    L: Listener,
+    L::Addr: Debug,
+    for<'a> ClientAddr: Connected<IncomingStream<'a, L>>,
+{
+    tracing::info!("Listening on {:?}", listener.local_addr().unwrap());
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<ClientAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
+}
 
-    tracing::info!("Listening on {}", listener.local_addr().unwrap());
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+/// Reloads the configuration every time the SIGHUP signal is received
+#[cfg(unix)]
+async fn reload_on_sighup(state: ProxyState) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading the configuration");
+        // The error is already logged within the reload, and the current configuration is kept
+        let _ = state.reload();
+    }
 }
 
 /// Handles the shutdown signal for the Axum application for a graceful shutdown
@@ -114,3 +426,156 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::AzureError;
+    use axum::{extract::State, http::StatusCode};
+    use std::path::{Path, PathBuf};
+
+    /// Writes the configuration file accepting the given API key
+    fn write_config(path: &Path, api_key: &str) {
+        let config = format!(
+            r#"
+            [auth]
+            api_keys = ["{api_key}"]
+
+            [upstreams.tei]
+            host = "127.0.0.1"
+            port = 8080
+
+            [routes.embeddings]
+            upstream = "tei"
+            "#
+        );
+        std::fs::write(path, config).unwrap();
+    }
+
+    /// Builds the state of the proxy loaded from the configuration file
+    fn state_from_file(name: &str) -> (ProxyState, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "azure-openai-proxy-{name}-{}.toml",
+            std::process::id()
+        ));
+        write_config(&path, "sk-first");
+        let cli = Cli {
+            config: Some(path.clone()),
+            ..Default::default()
+        };
+        (ProxyState::new(Config::load(&cli).unwrap(), cli), path)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let (state, path) = state_from_file("reload");
+        let snapshot = state.config.load_full();
+
+        // The new configuration is swapped, whilst the in-flight requests keep their snapshot
+        write_config(&path, "sk-second");
+        let status = reload_handler(State(state.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(state.config.load().auth.api_keys, ["sk-second"]);
+        assert_eq!(snapshot.auth.api_keys, ["sk-first"]);
+
+        // An invalid configuration is rejected, and the current one is kept
+        std::fs::write(&path, "[routes.embeddings]\nupstream = \"tgi\"\n").unwrap();
+        let res = reload_handler(State(state.clone())).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(res, Err(AzureError::InvalidConfiguration(_))));
+        assert_eq!(state.config.load().auth.api_keys, ["sk-second"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reload_on_sighup() {
+        let (state, path) = state_from_file("sighup");
+
+        // The signal is handled before sending it, as otherwise it terminates the test process
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup()).unwrap();
+        tokio::spawn(reload_on_sighup(state.clone()));
+        write_config(&path, "sk-second");
+
+        // The signal is sent until the configuration is reloaded, as the reload loop may not be
+        // listening for it yet
+        for _ in 0..100 {
+            std::process::Command::new("kill")
+                .args(["-HUP", &std::process::id().to_string()])
+                .status()
+                .unwrap();
+            hangup.recv().await;
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            if state.config.load().auth.api_keys == ["sk-second"] {
+                break;
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(state.config.load().auth.api_keys, ["sk-second"]);
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_over_tcp() {
+        let listener = bind_listener("127.0.0.1", &0, ListenFd::empty()).await;
+        let ProxyListener::Tcp(listener) = listener else {
+            panic!("the listener should be a TCP socket");
+        };
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_listener_over_unix_socket() {
+        let socket_path = std::env::temp_dir().join(format!(
+            "azure-openai-proxy-listener-{}.sock",
+            std::process::id()
+        ));
+        let host = format!("unix://{}", socket_path.display());
+
+        // The socket left behind by a previous run is replaced rather than failing to bind
+        drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+        assert!(socket_path.exists());
+        let listener = bind_listener(&host, &0, ListenFd::empty()).await;
+        std::fs::remove_file(&socket_path).unwrap();
+        assert!(matches!(listener, ProxyListener::Unix(_)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_bind_listener_inherited() {
+        use std::os::fd::IntoRawFd;
+
+        // Inherits the sockets as systemd would, i.e. via `LISTEN_FDS` starting at the given file
+        // descriptor, which `ListenFd` takes the ownership of
+        fn inherit(fd: i32) -> ListenFd {
+            // SAFETY: no other test reads or writes the `LISTEN_*` variables, and those are
+            // removed right after being read
+            unsafe {
+                std::env::set_var("LISTEN_PID", std::process::id().to_string());
+                std::env::set_var("LISTEN_FDS", "1");
+                std::env::set_var("LISTEN_FDS_FIRST_FD", fd.to_string());
+            }
+            let listenfd = ListenFd::from_env();
+            unsafe { std::env::remove_var("LISTEN_FDS_FIRST_FD") };
+            listenfd
+        }
+
+        // Both the host and port are ignored in favour of the inherited socket
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = bind_listener("unix:///ignored.sock", &0, inherit(tcp.into_raw_fd())).await;
+        let ProxyListener::Tcp(listener) = listener else {
+            panic!("the inherited listener should be a TCP socket");
+        };
+        assert_eq!(listener.local_addr().unwrap(), addr);
+
+        let socket_path = std::env::temp_dir().join(format!(
+            "azure-openai-proxy-inherited-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket_path);
+        let unix = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let listener = bind_listener("127.0.0.1", &0, inherit(unix.into_raw_fd())).await;
+        std::fs::remove_file(&socket_path).unwrap();
+        assert!(matches!(listener, ProxyListener::Unix(_)));
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Maximum number of rate limit keys tracked, before evicting the ones whose buckets are full
+/// again i.e. the ones that have not sent any request within the last minute
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Token bucket refilled continuously at `per_minute / 60` per second, holding at most one minute
+/// worth of capacity, so that short bursts are allowed as long as the average rate is respected
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Returns the bucket refilled up to now, creating it if it doesn't exist yet, as the limits
+    /// can be enabled on reload
+    fn refilled(bucket: &mut Option<Self>, per_minute: u64, now: Instant) -> &mut Self {
+        let bucket = bucket.get_or_insert_with(|| Self {
+            available: per_minute as f64,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.available =
+            (bucket.available + elapsed * per_minute as f64 / 60.0).min(per_minute as f64);
+        bucket.updated = now;
+        bucket
+    }
+
+    /// Returns how long to wait until the given amount is available, if not available already
+    fn wait_for(&self, amount: f64, per_minute: u64) -> Option<Duration> {
+        (self.available < amount)
+            .then(|| Duration::from_secs_f64((amount - self.available) * 60.0 / per_minute as f64))
+    }
+}
+
+/// Both the request and the token buckets of a rate limit key
+#[derive(Debug, Default)]
+struct Buckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl Buckets {
+    /// Returns whether both buckets are full i.e. the key has not been used within the last minute
+    fn is_full(&mut self, limits: Limits, now: Instant) -> bool {
+        [
+            (&mut self.requests, limits.requests_per_minute),
+            (&mut self.tokens, limits.tokens_per_minute),
+        ]
+        .into_iter()
+        .all(|(bucket, per_minute)| {
+            per_minute.is_none_or(|per_minute| {
+                TokenBucket::refilled(bucket, per_minute, now).available >= per_minute as f64
+            })
+        })
+    }
+}
+
+/// The limits applied to each rate limit key, read from the current configuration on every
+/// request so that those can be changed on reload
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub requests_per_minute: Option<u64>,
+    pub tokens_per_minute: Option<u64>,
+}
+
+/// The outcome of a rate limited request
+#[derive(Debug)]
+pub enum Decision {
+    /// The request is allowed, with the tokens reserved for it (if limited) until settled with
+    /// the usage
+    Allowed(RateLimitStatus, Option<TokenReservation>),
+
+    /// The request is rejected, and can be retried after the given duration
+    Throttled(RateLimitStatus, Duration),
+}
+
+/// The remaining requests and tokens of a rate limit key, reported via the
+/// `x-ratelimit-remaining-requests` and `x-ratelimit-remaining-tokens` headers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitStatus {
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+}
+
+/// Per-key rate limiter for both the requests and the tokens per minute, shared across all the
+/// proxy endpoints
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Buckets>>>,
+}
+
+impl RateLimiter {
+    /// Takes a request and the estimated number of tokens from the buckets of the key, only if
+    /// both are available, otherwise the request is throttled
+    pub fn acquire(&self, key: &str, estimated_tokens: u64, limits: Limits) -> Decision {
+        self.acquire_at(key, estimated_tokens, limits, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        key: &str,
+        estimated_tokens: u64,
+        limits: Limits,
+        now: Instant,
+    ) -> Decision {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_KEYS && !buckets.contains_key(key) {
+            buckets.retain(|_, buckets| !buckets.is_full(limits, now));
+        }
+        let buckets = buckets.entry(key.to_string()).or_default();
+
+        let mut requests = limits.requests_per_minute.map(|per_minute| {
+            let bucket = TokenBucket::refilled(&mut buckets.requests, per_minute, now);
+            (bucket, 1.0, per_minute)
+        });
+        // The estimate is capped to the capacity, as it would never be available otherwise
+        let mut tokens = limits.tokens_per_minute.map(|per_minute| {
+            let bucket = TokenBucket::refilled(&mut buckets.tokens, per_minute, now);
+            (
+                bucket,
+                (estimated_tokens as f64).min(per_minute as f64),
+                per_minute,
+            )
+        });
+
+        let wait = requests
+            .iter()
+            .chain(tokens.iter())
+            .filter_map(|(bucket, amount, per_minute)| bucket.wait_for(*amount, *per_minute))
+            .max();
+        if wait.is_none() {
+            for (bucket, amount, _) in requests.iter_mut().chain(tokens.iter_mut()) {
+                bucket.available -= *amount;
+            }
+        }
+
+        let reservation = tokens
+            .as_ref()
+            .map(|(_, amount, per_minute)| TokenReservation {
+                limiter: self.clone(),
+                key: key.to_string(),
+                reserved_tokens: *amount as u64,
+                tokens_per_minute: *per_minute,
+            });
+        let status = RateLimitStatus {
+            remaining_requests: requests.map(|(bucket, ..)| bucket.available.max(0.0) as u64),
+            remaining_tokens: tokens.map(|(bucket, ..)| bucket.available.max(0.0) as u64),
+        };
+
+        match wait {
+            Some(wait) => Decision::Throttled(status, wait),
+            None => Decision::Allowed(status, reservation),
+        }
+    }
+}
+
+/// The tokens reserved for an allowed request based on the estimate, which are corrected with the
+/// actual usage reported by the upstream once the response has been sent
+#[derive(Debug, Clone)]
+pub struct TokenReservation {
+    limiter: RateLimiter,
+    key: String,
+    reserved_tokens: u64,
+    tokens_per_minute: u64,
+}
+
+impl TokenReservation {
+    /// Corrects the tokens taken from the bucket with the actual usage, where the bucket can go
+    /// into debt (up to one minute worth of tokens) if the usage was larger than the estimate
+    pub fn settle(self, total_tokens: u64) {
+        let mut buckets = self.limiter.buckets.lock().unwrap();
+        if let Some(bucket) = buckets
+            .get_mut(&self.key)
+            .and_then(|buckets| buckets.tokens.as_mut())
+        {
+            let per_minute = self.tokens_per_minute as f64;
+            let correction = self.reserved_tokens as f64 - total_tokens as f64;
+            bucket.available = (bucket.available + correction).clamp(-per_minute, per_minute);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_and_refill() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            requests_per_minute: Some(2),
+            tokens_per_minute: Some(100),
+        };
+        let now = Instant::now();
+
+        assert!(matches!(
+            limiter.acquire_at("key", 40, limits, now),
+            Decision::Allowed(status, _) if status.remaining_requests == Some(1) && status.remaining_tokens == Some(60)
+        ));
+        // Throttled on tokens, as only 60 tokens are left, until 20 tokens are refilled i.e. 12s
+        let Decision::Throttled(status, wait) = limiter.acquire_at("key", 80, limits, now) else {
+            panic!("expected the request to be throttled");
+        };
+        assert_eq!(status.remaining_requests, Some(1));
+        assert_eq!(wait, Duration::from_secs(12));
+
+        // Other keys have their own buckets
+        assert!(matches!(
+            limiter.acquire_at("other", 80, limits, now),
+            Decision::Allowed(..)
+        ));
+
+        assert!(matches!(
+            limiter.acquire_at("key", 80, limits, now + Duration::from_secs(12)),
+            Decision::Allowed(status, _) if status.remaining_requests == Some(0)
+        ));
+    }
+
+    #[test]
+    fn test_settle_with_usage() {
+        let limiter = RateLimiter::default();
+        let limits = Limits {
+            requests_per_minute: None,
+            tokens_per_minute: Some(100),
+        };
+        let now = Instant::now();
+
+        let Decision::Allowed(_, Some(reservation)) = limiter.acquire_at("key", 50, limits, now)
+        else {
+            panic!("expected the request to be allowed");
+        };
+        // The actual usage was lower than estimated, so the difference is given back
+        reservation.settle(10);
+
+        assert!(matches!(
+            limiter.acquire_at("key", 0, limits, now),
+            Decision::Allowed(status, _) if status.remaining_tokens == Some(90)
+        ));
+    }
+}
@@ -13,6 +13,7 @@ pub struct FunctionCall {
     name: String,
 }
 
+#[allow(unused)]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FunctionDefinition {
     description: String,
@@ -135,7 +136,7 @@ pub struct FunctionSpec {
 pub struct ChatRequest {
     /// ID of the specific AI model to use, if more than one model is available on the endpoint.
     #[serde(skip_serializing_if = "Option::is_none")]
-    model: Option<String>,
+    pub model: Option<String>,
 
     /// The collection of context messages associated with this chat completions request. Typical
     /// usage begins with a chat message for the System role that provides instructions for the
@@ -204,6 +205,7 @@ pub struct ChatRequest {
     ///   which ensures the model will match your supplied JSON schema.
     /// - Setting to { "type": "json_object" } enables JSON mode, which ensures the message the
     ///   model generates is valid JSON.
+    ///
     /// Important: when using JSON mode, you must also instruct the model to produce JSON yourself
     /// via a system or user message. Without this, the model may generate an unending stream of
     /// whitespace until the generation reaches the token limit, resulting in a long-running and
@@ -226,14 +228,35 @@ pub struct ChatRequest {
     pub extra_parameters: HashMap<String, serde_json::Value>,
 }
 
-impl Into<axum::body::Body> for ChatRequest {
-    fn into(self) -> axum::body::Body {
-        let bytes = serde_json::to_vec(&self).unwrap();
-        tracing::debug!(
-            "Serialized ChatRequest JSON: {}",
-            String::from_utf8_lossy(&bytes)
+impl ChatRequest {
+    /// Returns the prefix of the conversation shared across the requests, used to route those to
+    /// the same upstream: the system messages, plus the first `chars` characters of the rest of
+    /// the serialized messages
+    pub fn prefix(&self, chars: usize) -> String {
+        let (system, rest): (Vec<_>, Vec<_>) = self
+            .messages
+            .iter()
+            .partition(|message| matches!(message, ChatRequestMessage::System { .. }));
+        let mut prefix = serde_json::to_string(&system).unwrap_or_default();
+        prefix.extend(
+            serde_json::to_string(&rest)
+                .unwrap_or_default()
+                .chars()
+                .take(chars),
         );
-        axum::body::Body::from(bytes)
+        prefix
+    }
+
+    /// Returns whether the request is expected to get the same response when repeated, i.e. not
+    /// streamed and with either a `temperature` of 0 or a fixed `seed`
+    pub fn is_deterministic(&self) -> bool {
+        self.stream != Some(true) && (self.temperature == Some(0.0) || self.seed.is_some())
+    }
+}
+
+impl From<&ChatRequest> for axum::body::Bytes {
+    fn from(value: &ChatRequest) -> Self {
+        axum::body::Bytes::from(serde_json::to_vec(value).unwrap())
     }
 }
 
@@ -258,4 +281,20 @@ mod tests {
         let serialized = serde_json::to_value(input).unwrap();
         assert_eq!(serialized, payload);
     }
+
+    #[test]
+    fn test_prefix() {
+        let input: ChatRequest = serde_json::from_value(json!({
+            "messages": [
+                {"role": "user", "content": "Where's Paris?"},
+                {"role": "system", "content": "You are a pirate"}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            input.prefix(15),
+            r#"[{"role":"system","content":"You are a pirate"}][{"role":"user""#
+        );
+    }
 }
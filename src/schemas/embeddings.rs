@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum EmbeddingInput {
     Single(String),
@@ -12,7 +12,7 @@ pub enum EmbeddingInput {
 /// ubinary and binary, may reduce storage costs without sacrificing the integrity of the data.
 ///
 /// Reference: https://learn.microsoft.com/en-us/rest/api/aifoundry/model-inference/get-embeddings/get-embeddings?view=rest-aifoundry-model-inference-2024-05-01-preview&tabs=HTTP#embeddingencodingformat
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum EmbeddingEncodingFormat {
     /// Get back binary representation of the embeddings encoded as Base64 string. OpenAI Python
@@ -39,7 +39,7 @@ pub enum EmbeddingEncodingFormat {
 /// Represents the input types used for embedding search.
 ///
 /// Reference: https://learn.microsoft.com/en-us/rest/api/aifoundry/model-inference/get-embeddings/get-embeddings?view=rest-aifoundry-model-inference-2024-05-01-preview&tabs=HTTP#embeddinginputtype
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "lowercase")]
 pub enum EmbeddingInputType {
     /// Indicates the input represents a document that is stored in a vector database.
@@ -53,14 +53,14 @@ pub enum EmbeddingInputType {
     Text,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbeddingsRequest {
     /// Input text to embed, encoded as a string or array of tokens. To embed multiple inputs in a
     /// single request, pass an array of strings or array of token arrays.
     input: EmbeddingInput,
 
     /// ID of the specific AI model to use, if more than one model is available on the endpoint.
-    model: String,
+    pub model: String,
 
     /// The number of dimensions the resulting output embeddings should have. Passing null causes
     /// the model to use its default value. Returns a 422 error if the model doesn't support the
@@ -84,13 +84,41 @@ pub struct EmbeddingsRequest {
     pub extra_parameters: HashMap<String, serde_json::Value>,
 }
 
-impl Into<axum::body::Body> for EmbeddingsRequest {
-    fn into(self) -> axum::body::Body {
-        let bytes = serde_json::to_vec(&self).unwrap();
-        tracing::debug!(
-            "Serialized EmbeddingsRequest JSON: {}",
-            String::from_utf8_lossy(&bytes)
-        );
-        axum::body::Body::from(bytes)
+impl EmbeddingsRequest {
+    /// Returns the inputs to embed, in order
+    pub fn inputs(&self) -> Vec<&str> {
+        match &self.input {
+            EmbeddingInput::Single(input) => vec![input.as_str()],
+            EmbeddingInput::Batch(inputs) => inputs.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Returns the request serialized without its inputs and with the fields sorted, i.e. the
+    /// parameters that need to match across requests for their inputs to be embedded alike
+    pub fn parameters(&self) -> String {
+        let mut parameters = serde_json::to_value(self).unwrap_or_default();
+        if let Some(parameters) = parameters.as_object_mut() {
+            parameters.remove("input");
+        }
+        parameters.to_string()
+    }
+
+    /// Returns the same request with the given batch of inputs instead, without copying the
+    /// current inputs
+    pub fn with_inputs(&self, inputs: Vec<String>) -> Self {
+        Self {
+            input: EmbeddingInput::Batch(inputs),
+            model: self.model.clone(),
+            dimensions: self.dimensions,
+            encoding_format: self.encoding_format.clone(),
+            input_type: self.input_type.clone(),
+            extra_parameters: self.extra_parameters.clone(),
+        }
+    }
+}
+
+impl From<&EmbeddingsRequest> for axum::body::Bytes {
+    fn from(value: &EmbeddingsRequest) -> Self {
+        axum::body::Bytes::from(serde_json::to_vec(value).unwrap())
     }
 }
@@ -0,0 +1,21 @@
+use crate::{
+    circuit_breaker::CircuitState,
+    health_check::{HealthStatus, UpstreamHealth},
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Aggregated health of the proxy and its upstreams
+#[derive(Serialize, Debug)]
+pub struct HealthResponse {
+    /// The aggregated health status, unhealthy if any of the upstreams serving a route or a model
+    /// is unhealthy (or every upstream of a pool), ignoring the rest of the upstreams
+    pub status: HealthStatus,
+
+    /// The health of each upstream, indexed by name
+    pub upstreams: BTreeMap<String, UpstreamHealth>,
+
+    /// The state of the circuit breaker of each upstream that has received requests
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub circuits: BTreeMap<String, CircuitState>,
+}
@@ -1,4 +1,6 @@
 pub mod azure;
 pub mod chat_completions;
 pub mod embeddings;
+pub mod health;
 pub mod info;
+pub mod usage;
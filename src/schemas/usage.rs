@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// The token usage reported by the upstream within the response, where the embeddings responses
+/// only contain both the `prompt_tokens` and the `total_tokens`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Usage {
+    /// The number of tokens within the prompt
+    pub prompt_tokens: u64,
+
+    /// The number of tokens generated
+    pub completion_tokens: u64,
+
+    /// The total number of tokens, being the sum of both the prompt and the completion ones
+    pub total_tokens: u64,
+}
@@ -0,0 +1,370 @@
+use crate::{
+    config::ResolvedUpstream, middlewares::request_id::REQUEST_ID, proxy::ProxyState,
+    utils::random_u64,
+};
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::Request,
+    http::header::CONTENT_TYPE,
+    response::Response,
+};
+use http_body_util::BodyExt;
+use serde_json::Value;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::{oneshot, Semaphore};
+
+/// Maximum size in bytes of the responses buffered to diff those, so that larger responses (e.g.
+/// huge embeddings batches) are not diffed
+const MAX_DIFF_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Timeout in seconds of the shadow requests, if the route has no `request_secs` timeout
+const DEFAULT_SHADOW_TIMEOUT_SECS: u64 = 300;
+
+/// Maximum number of requests in flight to the shadow upstreams at once, across all of those
+const MAX_IN_FLIGHT_SHADOW_REQUESTS: usize = 256;
+
+/// Maximum number of differences logged for each shadow response
+const MAX_LOGGED_DIFFERENCES: usize = 10;
+
+/// The fields expected to differ across any two responses
+const IGNORED_FIELDS: &[&str] = &["id", "created", "system_fingerprint"];
+
+/// Relative tolerance when comparing numbers, so that e.g. the embeddings computed by different
+/// versions of the engine are not reported as different due to floating point noise
+const NUMBER_TOLERANCE: f64 = 1e-3;
+
+/// Returns whether the request is sampled to be mirrored, with the given percentage
+pub fn sample(percent: f64) -> bool {
+    (random_u64() % 10_000) < (percent * 100.0) as u64
+}
+
+/// Limiter of the requests in flight to the shadow upstreams, so that a slow shadow upstream does
+/// not pile up the mirrored requests (along with their bodies) in the background
+#[derive(Debug, Clone)]
+pub struct ShadowLimiter(Arc<Semaphore>);
+
+impl ShadowLimiter {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_in_flight)))
+    }
+}
+
+impl Default for ShadowLimiter {
+    fn default() -> Self {
+        Self::new(MAX_IN_FLIGHT_SHADOW_REQUESTS)
+    }
+}
+
+/// The body of the response of the upstream, sent to the shadow request once dropped (i.e. once
+/// the response has been fully sent to the client) to be diffed against the shadow response
+pub struct PrimaryBody {
+    tx: Option<oneshot::Sender<Option<Bytes>>>,
+    bytes: Vec<u8>,
+    skipped: bool,
+}
+
+impl PrimaryBody {
+    /// Buffers the response body as it's sent to the client, unless too large, a streamed
+    /// response, or an error response
+    pub fn observe(mut self, res: Response) -> Response {
+        let streaming = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+        if streaming || !res.status().is_success() {
+            self.skipped = true;
+            return res;
+        }
+
+        res.map(|body| {
+            Body::new(body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref()
+                    && !self.skipped
+                {
+                    if self.bytes.len() + data.len() > MAX_DIFF_BODY_BYTES {
+                        self.skipped = true;
+                        self.bytes = Vec::new();
+                    } else {
+                        self.bytes.extend_from_slice(data);
+                    }
+                }
+                frame
+            }))
+        })
+    }
+}
+
+impl Drop for PrimaryBody {
+    fn drop(&mut self) {
+        // If the client closed the connection early, the body is not valid JSON so not diffed
+        if let Some(tx) = self.tx.take() {
+            let bytes = std::mem::take(&mut self.bytes);
+            let _ = tx.send((!self.skipped).then(|| Bytes::from(bytes)));
+        }
+    }
+}
+
+/// Mirrors the request to the shadow upstream in the background, logging its status and latency
+/// once completed, along with the differences with the response of the upstream if `diff` is set,
+/// for which the body of the upstream response is to be sent via the returned `PrimaryBody`. The
+/// shadow requests are sent once without retries, and their responses are discarded. If too many
+/// shadow requests are in flight already, the request is not mirrored at all.
+pub fn mirror(
+    state: &ProxyState,
+    upstream: &ResolvedUpstream,
+    shadow: ResolvedUpstream,
+    req: Request<Bytes>,
+    diff: bool,
+) -> Option<PrimaryBody> {
+    let Ok(permit) = state.shadow_limiter.0.clone().try_acquire_owned() else {
+        state.metrics.record_shadow_request_dropped(&shadow.name);
+        tracing::debug!(
+            "Not mirroring the request to '{}', too many shadow requests in flight",
+            shadow.name
+        );
+        return None;
+    };
+    tracing::info!(
+        "Mirroring {} request to {} (shadow upstream '{}')",
+        req.method(),
+        req.uri(),
+        shadow.name
+    );
+    let (tx, rx) = oneshot::channel();
+    let primary = diff.then_some(PrimaryBody {
+        tx: Some(tx),
+        bytes: Vec::new(),
+        skipped: false,
+    });
+
+    let client = state.client.clone();
+    let metrics = state.metrics.clone();
+    let upstream = upstream.name.clone();
+    // Captured as the shadow request outlives the request scope
+    let request_id = REQUEST_ID.try_with(|request_id| request_id.clone()).ok();
+    let timeout = Duration::from_secs(
+        shadow
+            .timeouts
+            .request_secs
+            .unwrap_or(DEFAULT_SHADOW_TIMEOUT_SECS),
+    );
+
+    tokio::spawn(async move {
+        let _permit = permit;
+        let start = Instant::now();
+        let request = async {
+            let res = client
+                .request(req.map(Body::from))
+                .await
+                .map_err(|e| e.to_string())?;
+            let status = res.status();
+            // Fully read, so that the latency includes the whole generation
+            let body = to_bytes(Body::new(res.into_body()), MAX_DIFF_BODY_BYTES).await;
+            Ok::<_, String>((status, body.ok()))
+        };
+        let result = tokio::time::timeout(timeout, request)
+            .await
+            .unwrap_or_else(|_| Err(format!("timed out after {}s", timeout.as_secs())));
+        let latency = start.elapsed();
+
+        let (status, body) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                metrics.record_shadow_request(&shadow.name, "error", latency);
+                tracing::warn!(
+                    request_id,
+                    upstream,
+                    shadow = shadow.name,
+                    "Shadow request failed with: {e}"
+                );
+                return;
+            }
+        };
+        metrics.record_shadow_request(&shadow.name, status.as_str(), latency);
+
+        let differences = match (diff, body) {
+            (true, Some(body)) => match rx.await {
+                Ok(Some(primary)) => differences(&primary, &body).ok(),
+                _ => None,
+            },
+            _ => None,
+        };
+        tracing::info!(
+            request_id,
+            upstream,
+            shadow = shadow.name,
+            status = status.as_u16(),
+            latency_ms = latency.as_secs_f64() * 1000.0,
+            differences = differences.as_ref().map(Vec::len),
+            first_differences = differences.as_ref().map(|differences| differences
+                [..differences.len().min(MAX_LOGGED_DIFFERENCES)]
+                .join(", ")),
+            "Shadow request completed"
+        );
+    });
+
+    primary
+}
+
+/// Returns the paths of the fields that differ between the JSON responses of the upstream and the
+/// shadow upstream, ignoring the fields that differ across any two responses
+fn differences(primary: &[u8], shadow: &[u8]) -> Result<Vec<String>, serde_json::Error> {
+    let primary = serde_json::from_slice::<Value>(primary)?;
+    let shadow = serde_json::from_slice::<Value>(shadow)?;
+    let mut differences = Vec::new();
+    diff_values("$", &primary, &shadow, &mut differences);
+    Ok(differences)
+}
+
+fn diff_values(path: &str, primary: &Value, shadow: &Value, differences: &mut Vec<String>) {
+    match (primary, shadow) {
+        (Value::Object(primary), Value::Object(shadow)) => {
+            let keys = primary
+                .keys()
+                .chain(shadow.keys().filter(|key| !primary.contains_key(*key)));
+            for key in keys.filter(|key| !IGNORED_FIELDS.contains(&key.as_str())) {
+                let path = format!("{path}.{key}");
+                match (primary.get(key), shadow.get(key)) {
+                    (Some(primary), Some(shadow)) => {
+                        diff_values(&path, primary, shadow, differences)
+                    }
+                    _ => differences.push(path),
+                }
+            }
+        }
+        (Value::Array(primary), Value::Array(shadow)) if primary.len() == shadow.len() => {
+            for (index, (primary, shadow)) in primary.iter().zip(shadow).enumerate() {
+                diff_values(&format!("{path}[{index}]"), primary, shadow, differences);
+            }
+        }
+        // The integers (e.g. token counts) are compared exactly, unlike the floats
+        (Value::Number(primary), Value::Number(shadow)) if primary.is_f64() || shadow.is_f64() => {
+            let (primary, shadow) = (
+                primary.as_f64().unwrap_or_default(),
+                shadow.as_f64().unwrap_or_default(),
+            );
+            if (primary - shadow).abs()
+                > NUMBER_TOLERANCE * primary.abs().max(shadow.abs()).max(1.0)
+            {
+                differences.push(path.to_string());
+            }
+        }
+        (primary, shadow) => {
+            if primary != shadow {
+                differences.push(path.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{append_path_to_uri, serve_locally};
+    use axum::{
+        http::StatusCode,
+        response::{IntoResponse, Json},
+        routing::post,
+        Router,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn test_sample() {
+        assert!((0..1000).all(|_| !sample(0.0)));
+        assert!((0..1000).all(|_| sample(100.0)));
+        let sampled = (0..10_000).filter(|_| sample(5.0)).count();
+        assert!((300..700).contains(&sampled), "{sampled} sampled");
+    }
+
+    #[tokio::test]
+    async fn test_primary_body() {
+        // Returns the body sent to the client, and the one handed to the diff once dropped
+        let observe = async |res: Response| {
+            let (tx, rx) = oneshot::channel();
+            let primary = PrimaryBody {
+                tx: Some(tx),
+                bytes: Vec::new(),
+                skipped: false,
+            };
+            let body = to_bytes(primary.observe(res).into_body(), usize::MAX).await;
+            (body.unwrap(), rx.await.unwrap())
+        };
+
+        let (body, primary) = observe(Json(json!({"model": "bge"})).into_response()).await;
+        assert_eq!(primary, Some(body));
+
+        // Neither the streamed nor the error responses are diffed
+        let streamed = ([(CONTENT_TYPE, "text/event-stream")], "data: {}\n\n").into_response();
+        assert_eq!(observe(streamed).await.1, None);
+        let error = (StatusCode::BAD_REQUEST, "invalid").into_response();
+        assert_eq!(observe(error).await.1, None);
+    }
+
+    #[tokio::test]
+    async fn test_mirror_is_capped() {
+        // The shadow upstream never responds, so the mirrored requests stay in flight
+        let hanging = async || std::future::pending::<()>().await;
+        let uri = serve_locally(Router::new().route("/v1/embeddings", post(hanging))).await;
+        let mut state = ProxyState::from_toml("");
+        state.shadow_limiter = ShadowLimiter::new(1);
+        let shadow = ResolvedUpstream {
+            name: "tei-next".to_string(),
+            uri,
+            model: None,
+            timeouts: Default::default(),
+            fallback: None,
+            variant: None,
+            shadow: None,
+        };
+        let upstream = ResolvedUpstream {
+            name: "tei".to_string(),
+            ..shadow.clone()
+        };
+        let req = || {
+            let uri = append_path_to_uri(shadow.uri.clone(), "/v1/embeddings");
+            Request::post(uri).body(Bytes::new()).unwrap()
+        };
+
+        assert!(mirror(&state, &upstream, shadow.clone(), req(), true).is_some());
+        assert!(mirror(&state, &upstream, shadow.clone(), req(), true).is_none());
+        assert!(state.metrics.encode().contains(
+            r#"azure_openai_proxy_shadow_requests_dropped_total{upstream="tei-next"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_differences() {
+        let primary = json!({
+            "id": "chatcmpl-1",
+            "model": "phi",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Paris"}}],
+            "usage": {"prompt_tokens": 1000, "completion_tokens": 1},
+            "data": [{"embedding": [0.12345, -1.0]}]
+        });
+        let shadow = json!({
+            "id": "chatcmpl-2",
+            "model": "phi",
+            "choices": [{"index": 0, "message": {"role": "assistant", "content": "Lyon"}}],
+            "usage": {"prompt_tokens": 1001, "completion_tokens": 1, "total_tokens": 1002},
+            "data": [{"embedding": [0.12346, -1.0]}]
+        });
+
+        let differences = differences(
+            &serde_json::to_vec(&primary).unwrap(),
+            &serde_json::to_vec(&shadow).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            differences,
+            [
+                "$.choices[0].message.content",
+                "$.usage.prompt_tokens",
+                "$.usage.total_tokens"
+            ]
+        );
+    }
+}
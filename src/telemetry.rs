@@ -0,0 +1,67 @@
+use crate::config::{Config, LogFormat};
+use opentelemetry::{global, trace::TracerProvider};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Initializes the tracing subscriber, logging the events to the standard output in the configured
+/// format and, if the OTLP endpoint is configured, exporting the spans to the OpenTelemetry
+/// collector too. Returns the tracer provider (if any), which needs to be shut down on exit so that
+/// the pending spans are flushed.
+pub fn init_tracing(config: &Config) -> Option<SdkTracerProvider> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        format!("{}={}", env!("CARGO_CRATE_NAME"), config.logging.level).into()
+    });
+
+    let provider = config.tracing.otlp_endpoint.as_ref().and_then(|endpoint| {
+        // The endpoint is the base URL of the collector, as in `OTEL_EXPORTER_OTLP_ENDPOINT`
+        let endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&endpoint)
+            .build()
+            .inspect_err(|e| eprintln!("Failed to build the OTLP exporter for {endpoint}: {e}"))
+            .ok()?;
+
+        Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(exporter)
+                .with_resource(
+                    Resource::builder()
+                        .with_service_name(config.tracing.service_name.clone())
+                        .build(),
+                )
+                .build(),
+        )
+    });
+
+    // The W3C trace context propagator reads the `traceparent` and `tracestate` headers from the
+    // incoming requests, and writes those on the requests to the upstreams
+    let otel_layer = provider.as_ref().map(|provider| {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+    });
+
+    let (text_layer, json_layer) = match config.logging.format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer().compact()), None),
+        LogFormat::Json => (
+            None,
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(true)
+                    .with_span_list(false),
+            ),
+        ),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer)
+        .with(otel_layer)
+        .init();
+
+    provider
+}
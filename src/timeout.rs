@@ -0,0 +1,186 @@
+use crate::{errors::AzureError, metrics::Metrics, middlewares::request_id::REQUEST_ID};
+use axum::body::{Body, Bytes, HttpBody};
+use hyper::body::Frame;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::{sleep, sleep_until, Instant, Sleep};
+
+/// Response body that enforces both the idle timeout between chunks and the deadline of the whole
+/// request, ending the streamed responses with a terminal error event so that the clients can tell
+/// the stream apart from a completed one, and aborting the rest of the responses
+pub struct TimeoutBody {
+    inner: Body,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    streaming: bool,
+    upstream: String,
+    metrics: Metrics,
+    request_id: Option<String>,
+    done: bool,
+}
+
+impl TimeoutBody {
+    pub fn new(
+        inner: Body,
+        idle: Option<Duration>,
+        deadline: Option<Instant>,
+        streaming: bool,
+        upstream: &str,
+        metrics: &Metrics,
+    ) -> Self {
+        Self {
+            inner,
+            idle: idle.map(|idle| (idle, Box::pin(sleep(idle)))),
+            deadline: deadline.map(|deadline| Box::pin(sleep_until(deadline))),
+            streaming,
+            upstream: upstream.to_string(),
+            metrics: metrics.clone(),
+            // Captured as the body is polled outside of the request scope
+            request_id: REQUEST_ID.try_with(|request_id| request_id.clone()).ok(),
+            done: false,
+        }
+    }
+
+    /// Returns the error for the timeout that elapsed (if any)
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>) -> Option<AzureError> {
+        if let Some(deadline) = &mut self.deadline
+            && deadline.as_mut().poll(cx).is_ready()
+        {
+            return Some(AzureError::RequestTimeout(
+                "The request to the upstream exceeded its timeout.".to_string(),
+            ));
+        }
+        if let Some((idle, timer)) = &mut self.idle
+            && timer.as_mut().poll(cx).is_ready()
+        {
+            return Some(AzureError::GatewayTimeout(format!(
+                "The upstream sent no data for {}s.",
+                idle.as_secs()
+            )));
+        }
+        None
+    }
+}
+
+impl HttpBody for TimeoutBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        if let Poll::Ready(frame) = Pin::new(&mut this.inner).poll_frame(cx) {
+            if let Some((idle, timer)) = &mut this.idle {
+                timer.as_mut().reset(Instant::now() + *idle);
+            }
+            return Poll::Ready(frame);
+        }
+
+        let Some(error) = this.poll_timeouts(cx) else {
+            return Poll::Pending;
+        };
+        this.done = true;
+        this.metrics
+            .record_upstream_error(&this.upstream, "timeout");
+        tracing::warn!(
+            "Response from upstream '{}' timed out: {error}",
+            this.upstream
+        );
+
+        if this.streaming {
+            let event = match this.request_id.clone() {
+                Some(request_id) => REQUEST_ID.sync_scope(request_id, || error.into_event()),
+                None => error.into_event(),
+            };
+            Poll::Ready(Some(Ok(Frame::data(event))))
+        } else {
+            Poll::Ready(Some(Err(axum::Error::new(io::Error::new(
+                io::ErrorKind::TimedOut,
+                error.to_string(),
+            )))))
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done || self.inner.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{stream, StreamExt};
+    use http_body_util::BodyExt;
+
+    /// Body that sends a single chunk and then hangs, as a wedged upstream would
+    fn hanging_body() -> Body {
+        Body::from_stream(
+            stream::iter([Ok::<_, io::Error>(Bytes::from("data: {}\n\n"))])
+                .chain(stream::pending()),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_timeout() {
+        let metrics = Metrics::default();
+        let idle = Some(Duration::from_secs(5));
+        let mut body = TimeoutBody::new(hanging_body(), idle, None, false, "vllm", &metrics);
+
+        // The idle timeout restarts on every chunk
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let chunk = body.frame().await.unwrap().unwrap();
+        assert_eq!(chunk.into_data().unwrap(), "data: {}\n\n");
+        let start = Instant::now();
+        let error = body.frame().await.unwrap().unwrap_err();
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+        assert_eq!(error.to_string(), "The upstream sent no data for 5s.");
+        assert!(body.is_end_stream());
+        assert!(body.frame().await.is_none());
+
+        assert!(metrics.encode().contains(
+            r#"azure_openai_proxy_upstream_errors_total{class="timeout",upstream="vllm"} 1"#
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deadline_ends_stream_with_error_event() {
+        let metrics = Metrics::default();
+        let idle = Some(Duration::from_secs(5));
+        let deadline = Some(Instant::now() + Duration::from_secs(3));
+        let mut body = REQUEST_ID.sync_scope("request-id".to_string(), || {
+            TimeoutBody::new(hanging_body(), idle, deadline, true, "vllm", &metrics)
+        });
+
+        body.frame().await.unwrap().unwrap();
+        // The deadline elapses before the idle timeout, and the stream is ended with an error
+        // event including the request ID, even though the body is polled outside of its scope
+        let start = Instant::now();
+        let event = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+        let event = std::str::from_utf8(&event).unwrap();
+        let data = event
+            .strip_prefix("event: error\ndata: ")
+            .and_then(|data| data.strip_suffix("\n\n"))
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(data).unwrap(),
+            serde_json::json!({"error": {
+                "code": "Timeout",
+                "message": "The request to the upstream exceeded its timeout.",
+                "request_id": "request-id"
+            }})
+        );
+        assert!(body.frame().await.is_none());
+    }
+}
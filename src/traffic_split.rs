@@ -0,0 +1,111 @@
+use crate::{
+    config::{SplitConfig, StickyKey},
+    middlewares::auth::api_key,
+    utils::{random_u64, stable_hash},
+};
+use axum::http::{HeaderMap, HeaderName};
+
+/// Header with the variant of the traffic split the request was assigned to
+pub const VARIANT: HeaderName = HeaderName::from_static("x-variant");
+
+/// The traffic split and the variant a request was assigned to, added to the response extensions
+/// so that the variant is both logged and recorded on the metrics
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub split: String,
+    pub name: String,
+}
+
+/// Picks the variant of the traffic split for the request, following the weights of the variants,
+/// either at random or deterministically for the requests with the same sticky key, so that e.g.
+/// the same user is always served by the same variant (as long as the variants don't change)
+pub fn choose_variant(split: &SplitConfig, headers: &HeaderMap) -> Option<String> {
+    let total = split
+        .variants
+        .values()
+        .map(|variant| u64::from(variant.weight))
+        .sum::<u64>();
+    if total == 0 {
+        return None;
+    }
+
+    let sticky_key = match split.sticky {
+        Some(StickyKey::ApiKey) => api_key(headers),
+        Some(StickyKey::Header) => split
+            .sticky_header
+            .as_deref()
+            .and_then(|header| headers.get(header))
+            .and_then(|value| value.to_str().ok()),
+        None => None,
+    };
+    let mut point = match sticky_key {
+        Some(key) => stable_hash(key.as_bytes()) % total,
+        None => random_u64() % total,
+    };
+
+    for (name, variant) in &split.variants {
+        let weight = u64::from(variant.weight);
+        if point < weight {
+            return Some(name.clone());
+        }
+        point -= weight;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::VariantConfig;
+    use axum::http::HeaderValue;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn split(sticky: Option<StickyKey>) -> SplitConfig {
+        let variant = |upstream: &str, weight| VariantConfig {
+            upstream: upstream.to_string(),
+            model: None,
+            weight,
+        };
+        SplitConfig {
+            variants: BTreeMap::from([
+                ("base".to_string(), variant("vllm", 3)),
+                ("fine-tune".to_string(), variant("vllm-ft", 1)),
+                ("disabled".to_string(), variant("vllm-old", 0)),
+            ]),
+            sticky,
+            sticky_header: Some("x-user-id".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_choose_variant_by_weight() {
+        let split = split(None);
+        let mut counts = HashMap::new();
+        for _ in 0..4000 {
+            let variant = choose_variant(&split, &HeaderMap::new()).unwrap();
+            *counts.entry(variant).or_insert(0) += 1;
+        }
+
+        assert!(!counts.contains_key("disabled"));
+        // Roughly three times as many requests to the base variant, within a generous margin
+        assert!((2600..3400).contains(&counts["base"]), "{counts:?}");
+    }
+
+    #[test]
+    fn test_choose_variant_sticky() {
+        let split = split(Some(StickyKey::Header));
+        let variants = (0..100)
+            .map(|user| {
+                let mut headers = HeaderMap::new();
+                headers.insert("x-user-id", HeaderValue::from(user));
+                let variant = choose_variant(&split, &headers).unwrap();
+                // The same user is always assigned to the same variant
+                assert!((0..5).all(|_| choose_variant(&split, &headers).unwrap() == variant));
+                variant
+            })
+            .collect::<Vec<_>>();
+
+        assert!(variants.iter().any(|variant| variant == "base"));
+        assert!(variants.iter().any(|variant| variant == "fine-tune"));
+    }
+}
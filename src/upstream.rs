@@ -0,0 +1,590 @@
+use crate::{
+    config::{ResolvedUpstream, RetryConfig},
+    connector::{ConnectTimeout, CONNECT_TIMEOUT},
+    errors::AzureError,
+    proxy::ProxyState,
+    shadow,
+    timeout::TimeoutBody,
+    traffic_split::{Variant, VARIANT},
+    utils::random_u64,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+use opentelemetry::global;
+use opentelemetry_http::HeaderInjector;
+use std::{error::Error, time::Duration};
+use tokio::time::{timeout_at, Instant};
+use tracing::{field::Empty, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Returns the backoff before the given retry (starting at 0), following an exponential backoff
+/// with full jitter i.e. a random duration up to the exponential backoff, so that the retries of
+/// concurrent requests are spread over time rather than hitting the upstream all at once
+fn backoff(config: &RetryConfig, retry: u32) -> Duration {
+    let max_backoff_ms = config
+        .initial_backoff_ms
+        .saturating_mul(2u64.saturating_pow(retry))
+        .min(config.max_backoff_ms);
+    Duration::from_millis(random_u64() % (max_backoff_ms + 1))
+}
+
+/// Default timeout in seconds to connect to the upstream, if not configured for the route
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+
+/// Header with the name of the upstream that served the response, either the resolved one or its
+/// fallback
+pub const SERVED_BY_UPSTREAM: HeaderName = HeaderName::from_static("x-upstream");
+
+/// Extension on the responses served by the fallback rather than the upstream itself, as those
+/// may have been generated by another model, which is kept once the response is split (e.g. out of
+/// an embeddings batch) unlike the `x-upstream` header, which is the one of the whole response
+#[derive(Debug, Clone, Copy)]
+pub struct Fallback;
+
+/// Sends the request built for the upstream, and fails over to the fallback of the upstream (if
+/// any) with the request built for the fallback instead (e.g. with a different model), if the
+/// upstream fails even after the retries, its circuit is open, or its queue is full. The requests
+/// that exceeded their whole timeout are not failed over, nor the error responses caused by the
+/// request itself (i.e. 4XX). If the request was assigned to a variant of a traffic split, then
+/// the variant is returned on the response, including the error ones. If the route has a shadow
+/// upstream, then a share of the requests are also mirrored to it, built for it too.
+pub async fn send_with_fallback(
+    state: &ProxyState,
+    upstream: &ResolvedUpstream,
+    headers: &HeaderMap,
+    mut build: impl FnMut(&ResolvedUpstream) -> Result<Request<Bytes>, AzureError>,
+) -> Result<Response, AzureError> {
+    let config = state.config.load_full();
+    // Mirrored along with the request to the upstream, so that both latencies are comparable
+    let primary = match &upstream.shadow {
+        Some(shadow) if shadow::sample(shadow.percent) => {
+            // The request is still sent to the upstream if it can't be built for the shadow
+            match state.resolve_shadow(&config, upstream, headers) {
+                Some(target) => match build(&target) {
+                    Ok(req) => shadow::mirror(state, upstream, target, req, shadow.diff),
+                    Err(e) => {
+                        state.metrics.record_shadow_request_error(&target.name);
+                        tracing::warn!(
+                            "Not mirroring the request to '{}', as it failed to be built: {e}",
+                            target.name
+                        );
+                        None
+                    }
+                },
+                None => None,
+            }
+        }
+        _ => None,
+    };
+
+    let mut result = send_request(state, upstream, proxied(build(upstream)?, upstream)).await;
+    let (mut served_by, mut failed_over) = (upstream.name.clone(), false);
+
+    let failed = match &result {
+        Ok(res) => res.status().is_server_error(),
+        Err(AzureError::RequestTimeout(_)) => false,
+        Err(_) => true,
+    };
+    if failed && let Some(fallback) = state.resolve_fallback(&config, upstream, headers) {
+        tracing::warn!(
+            "Upstream '{}' failed, failing over to '{}'",
+            upstream.name,
+            fallback.name
+        );
+        state
+            .metrics
+            .record_upstream_failover(&upstream.name, &fallback.name);
+        drop(result);
+        result = send_request(state, &fallback, proxied(build(&fallback)?, &fallback)).await;
+        (served_by, failed_over) = (fallback.name, true);
+    }
+
+    let mut res = match result {
+        Ok(mut res) => {
+            if let Ok(value) = HeaderValue::from_str(&served_by) {
+                res.headers_mut().insert(SERVED_BY_UPSTREAM, value);
+            }
+            if failed_over {
+                res.extensions_mut().insert(Fallback);
+            }
+            match primary {
+                Some(primary) => primary.observe(res),
+                None => res,
+            }
+        }
+        Err(error) if upstream.variant.is_some() => error.into_response(),
+        Err(error) => return Err(error),
+    };
+    insert_variant(&mut res, upstream);
+    Ok(res)
+}
+
+/// Logs the request being proxied to either the upstream or its fallback, as the ones mirrored to
+/// the shadow upstream are logged apart
+fn proxied(req: Request<Bytes>, upstream: &ResolvedUpstream) -> Request<Bytes> {
+    tracing::info!(
+        "Proxying {} request to {} (upstream '{}')",
+        req.method(),
+        req.uri(),
+        upstream.name
+    );
+    req
+}
+
+/// Returns the variant of the traffic split the request was assigned to (if any) on the response,
+/// via both the `x-variant` header and the `Variant` extension, so that the responses served from
+/// the cache are attributed to the variant too
+pub fn insert_variant(res: &mut Response, upstream: &ResolvedUpstream) {
+    if let Some((split, variant)) = &upstream.variant {
+        if let Ok(value) = HeaderValue::from_str(variant) {
+            res.headers_mut().insert(VARIANT, value);
+        }
+        res.extensions_mut().insert(Variant {
+            split: split.clone(),
+            name: variant.clone(),
+        });
+    }
+}
+
+/// Sends the request to the upstream within its own span, propagating the trace context via the
+/// `traceparent` and `tracestate` headers, and recording both the connection and the request
+/// failures, as well as the error responses, on the upstream error metrics. If the upstream has a
+/// concurrency limit, the request first waits for a slot, which is held until the response body
+/// has been fully sent, as the upstream keeps generating the streamed responses until then. While
+/// the circuit breaker of the upstream is open, the request is rejected right away instead.
+///
+/// The connection failures and the configured statuses are retried with backoff, replaying the
+/// buffered body; the retries only happen before the response is returned, so never once any byte
+/// has been sent to the client.
+///
+/// The timeouts of the route (or model) are enforced on the connection, the response headers and
+/// the response body, where the timeouts before the response map to either a 504 or a 408 (once
+/// the whole request timed out), and the ones after end the streamed responses with an error event.
+pub async fn send_request(
+    state: &ProxyState,
+    upstream: &ResolvedUpstream,
+    req: Request<Bytes>,
+) -> Result<Response, AzureError> {
+    let config = state.config.load_full();
+    let start = Instant::now();
+    let deadline = upstream
+        .timeouts
+        .request_secs
+        .map(|secs| start + Duration::from_secs(secs));
+    let mut retry = 0;
+
+    loop {
+        let result = send_attempt(state, upstream, req.clone(), deadline).await;
+
+        let reason = match &result {
+            Ok(res) if config.retry.statuses.contains(&res.status().as_u16()) => {
+                format!("http_{}", res.status().as_u16())
+            }
+            Err(UpstreamError::Connect(_)) => "connect".to_string(),
+            _ => return result.map_err(AzureError::from),
+        };
+
+        let backoff = backoff(&config.retry, retry);
+        if retry >= config.retry.max_retries
+            || start.elapsed() + backoff > Duration::from_secs(config.retry.max_retry_secs)
+            || deadline.is_some_and(|deadline| Instant::now() + backoff >= deadline)
+        {
+            return result.map_err(AzureError::from);
+        }
+
+        tracing::warn!(
+            "Retrying request to upstream '{}' after {}ms due to {reason} (retry {} of {})",
+            upstream.name,
+            backoff.as_millis(),
+            retry + 1,
+            config.retry.max_retries
+        );
+        state.metrics.record_upstream_retry(&upstream.name, &reason);
+        // Drops the failed response (if any) before waiting, so that its connection is released
+        drop(result);
+        tokio::time::sleep(backoff).await;
+        retry += 1;
+    }
+}
+
+/// The failure of an attempt to send the request to the upstream, where only the connection
+/// failures are retried as the upstream has not received the request in that case
+enum UpstreamError {
+    Connect(AzureError),
+    Other(AzureError),
+}
+
+impl From<UpstreamError> for AzureError {
+    fn from(error: UpstreamError) -> Self {
+        match error {
+            UpstreamError::Connect(error) | UpstreamError::Other(error) => error,
+        }
+    }
+}
+
+/// Sends a single attempt of the request to the upstream
+async fn send_attempt(
+    state: &ProxyState,
+    upstream: &ResolvedUpstream,
+    req: Request<Bytes>,
+    deadline: Option<Instant>,
+) -> Result<Response, UpstreamError> {
+    let name = upstream.name.as_str();
+    let mut req = req.map(Body::from);
+    let span = tracing::info_span!(
+        "upstream",
+        otel.kind = "client",
+        upstream = name,
+        url.full = %req.uri(),
+        http.response.status_code = Empty,
+    );
+    // Overrides the incoming trace context (if any) forwarded along with the rest of the headers,
+    // so that the upstream spans are children of the proxy one
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut HeaderInjector(req.headers_mut()))
+    });
+
+    // Fails fast while the upstream is failing, rather than waiting for it
+    let config = state.config.load_full();
+    let circuit = state
+        .circuit_breakers
+        .allow(name, &config.circuit_breaker, &state.metrics)
+        .map_err(UpstreamError::Other)?;
+
+    let permit = match config.upstreams.get(name) {
+        Some(upstream_config) => state
+            .concurrency
+            .acquire(name, upstream_config, &state.metrics)
+            .instrument(tracing::info_span!(parent: &span, "queue"))
+            .await
+            .map_err(UpstreamError::Other)?,
+        None => None,
+    };
+
+    let in_flight = state.load_balancer.start(name);
+
+    let timeouts = upstream.timeouts;
+    let connect_timeout = Duration::from_secs(
+        timeouts
+            .connect_secs
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+    );
+    let first_byte_deadline = timeouts
+        .first_byte_secs
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let response_deadline = match (first_byte_deadline, deadline) {
+        (Some(first_byte), Some(deadline)) => Some(first_byte.min(deadline)),
+        (first_byte, deadline) => first_byte.or(deadline),
+    };
+
+    let request = CONNECT_TIMEOUT
+        .scope(connect_timeout, state.client.request(req))
+        .instrument(span.clone());
+    let result = match response_deadline {
+        Some(response_deadline) => match timeout_at(response_deadline, request).await {
+            Ok(result) => result,
+            Err(_) => {
+                circuit.record(false);
+                state.metrics.record_upstream_error(name, "timeout");
+                let error = if deadline.is_some_and(|deadline| deadline <= response_deadline) {
+                    AzureError::RequestTimeout(
+                        "The request to the upstream exceeded its timeout.".to_string(),
+                    )
+                } else {
+                    AzureError::GatewayTimeout(format!(
+                        "The upstream '{name}' did not respond within {}s.",
+                        timeouts.first_byte_secs.unwrap_or_default()
+                    ))
+                };
+                return Err(UpstreamError::Other(error));
+            }
+        },
+        None => request.await,
+    };
+
+    match result {
+        Ok(res) => {
+            span.record("http.response.status_code", res.status().as_u16());
+            circuit.record(!res.status().is_server_error());
+            if res.status().is_server_error() {
+                state.metrics.record_upstream_error(name, "http_5xx");
+            } else if res.status().is_client_error() {
+                state.metrics.record_upstream_error(name, "http_4xx");
+            }
+            let streaming = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(|value| value.starts_with("text/event-stream"));
+            // Both the concurrency slot (if any) and the request in flight are held until the
+            // response body has been fully sent
+            let res = res.map(|body| {
+                Body::new(body.map_frame(move |frame| {
+                    let _ = (&permit, &in_flight);
+                    frame
+                }))
+            });
+            let idle = timeouts.idle_secs.map(Duration::from_secs);
+            let res = if idle.is_some() || deadline.is_some() {
+                res.map(|body| {
+                    Body::new(TimeoutBody::new(
+                        body,
+                        idle,
+                        deadline,
+                        streaming,
+                        name,
+                        &state.metrics,
+                    ))
+                })
+            } else {
+                res
+            };
+            Ok(res.into_response())
+        }
+        Err(e) if e.is_connect() => {
+            circuit.record(false);
+            state.metrics.record_upstream_error(name, "connect");
+            // The connect timeout is reported by the connector within the error sources
+            let mut source = e.source();
+            while let Some(error) = source {
+                if let Some(ConnectTimeout(timeout)) = error.downcast_ref::<ConnectTimeout>() {
+                    return Err(UpstreamError::Connect(AzureError::GatewayTimeout(format!(
+                        "Failed to connect to the upstream '{name}' within {}s.",
+                        timeout.as_secs()
+                    ))));
+                }
+                source = error.source();
+            }
+            Err(UpstreamError::Connect(AzureError::Upstream(
+                StatusCode::BAD_GATEWAY,
+                e.to_string(),
+            )))
+        }
+        Err(e) => {
+            circuit.record(false);
+            state.metrics.record_upstream_error(name, "request");
+            Err(UpstreamError::Other(AzureError::Upstream(
+                StatusCode::BAD_GATEWAY,
+                e.to_string(),
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        circuit_breaker::CircuitState,
+        utils::{append_path_to_uri, serve_locally},
+        UpstreamType,
+    };
+    use axum::{extract::Json, routing::post, Router};
+    use serde_json::{json, Value};
+
+    /// Serves a fake upstream responding with the status and echoing the model of each request,
+    /// after the delay
+    async fn fake_upstream(status: StatusCode, delay: Duration) -> u16 {
+        let handler = move |Json(body): Json<Value>| async move {
+            tokio::time::sleep(delay).await;
+            (
+                status,
+                body["model"].as_str().unwrap_or_default().to_string(),
+            )
+        };
+        let uri = serve_locally(Router::new().route("/v1/chat/completions", post(handler))).await;
+        uri.port_u16().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send_with_fallback() {
+        // Nothing listens on the port of the upstream that is down
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = listener.local_addr().unwrap().port();
+        drop(listener);
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.failing]
+            host = "127.0.0.1"
+            port = {}
+
+            [upstreams.invalid]
+            host = "127.0.0.1"
+            port = {}
+
+            [upstreams.slow]
+            host = "127.0.0.1"
+            port = {}
+
+            [upstreams.down]
+            host = "127.0.0.1"
+            port = {down}
+
+            [upstreams.cpu]
+            host = "127.0.0.1"
+            port = {}
+
+            [routes.chat-completions]
+            upstream = "cpu"
+
+            [models.failing]
+            upstream = "failing"
+            fallback = {{ upstream = "cpu", model = "phi-mini" }}
+
+            [models.invalid]
+            upstream = "invalid"
+            fallback = {{ upstream = "cpu", model = "phi-mini" }}
+
+            [models.slow]
+            upstream = "slow"
+            timeouts = {{ request_secs = 1 }}
+            fallback = {{ upstream = "cpu", model = "phi-mini" }}
+
+            [models.down]
+            upstream = "down"
+            model = "phi"
+            fallback = {{ upstream = "cpu" }}
+
+            [circuit_breaker]
+            enabled = true
+            consecutive_failures = 1
+            "#,
+            fake_upstream(StatusCode::INTERNAL_SERVER_ERROR, Duration::ZERO).await,
+            fake_upstream(StatusCode::BAD_REQUEST, Duration::ZERO).await,
+            fake_upstream(StatusCode::OK, Duration::from_secs(10)).await,
+            fake_upstream(StatusCode::OK, Duration::ZERO).await,
+        ));
+        // Returns the status, the `x-upstream` header and the model received by the upstream
+        let send = async |model: &str| {
+            let config = state.config.load_full();
+            let route = UpstreamType::ChatCompletions;
+            let headers = HeaderMap::new();
+            let upstream = state
+                .resolve(&config, &route, Some(model), &headers, None)
+                .unwrap();
+            let res = send_with_fallback(&state, &upstream, &headers, |target| {
+                let model = target.model.as_deref().unwrap_or(model);
+                let uri = append_path_to_uri(target.uri.clone(), "/v1/chat/completions");
+                Ok(Request::post(uri)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Bytes::from(json!({"model": model}).to_string()))
+                    .unwrap())
+            })
+            .await?;
+            let upstream = res.headers()[SERVED_BY_UPSTREAM]
+                .to_str()
+                .unwrap()
+                .to_string();
+            let status = res.status();
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            Ok::<_, AzureError>((status, upstream, String::from_utf8(body.to_vec()).unwrap()))
+        };
+
+        // The 5XX responses fail over, with the model of the fallback
+        assert_eq!(
+            send("failing").await.unwrap(),
+            (StatusCode::OK, "cpu".to_string(), "phi-mini".to_string())
+        );
+        // The 4XX responses are caused by the request itself, so those are returned as is
+        assert_eq!(
+            send("invalid").await.unwrap(),
+            (
+                StatusCode::BAD_REQUEST,
+                "invalid".to_string(),
+                "invalid".to_string()
+            )
+        );
+        // The requests that exceeded their whole timeout are not sent again
+        assert!(matches!(
+            send("slow").await,
+            Err(AzureError::RequestTimeout(_))
+        ));
+
+        // Both the connection failures and the open circuits fail over, with the model requested
+        // if the fallback does not define one, rather than the one of the upstream
+        for _ in 0..2 {
+            assert_eq!(
+                send("down").await.unwrap(),
+                (StatusCode::OK, "cpu".to_string(), "down".to_string())
+            );
+        }
+        assert_eq!(
+            state.circuit_breakers.snapshot()["down"],
+            CircuitState::Open
+        );
+        assert!(state.metrics.encode().contains(
+            r#"azure_openai_proxy_upstream_failovers_total{fallback="cpu",upstream="down"} 2"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shadow_build_error() {
+        let state = ProxyState::from_toml(&format!(
+            r#"
+            [upstreams.vllm]
+            host = "127.0.0.1"
+            port = {}
+
+            [upstreams.candidate]
+            host = "127.0.0.1"
+            port = {}
+
+            [routes.chat-completions]
+            upstream = "vllm"
+            shadow = {{ upstream = "candidate", percent = 100 }}
+            "#,
+            fake_upstream(StatusCode::OK, Duration::ZERO).await,
+            fake_upstream(StatusCode::OK, Duration::ZERO).await,
+        ));
+        let config = state.config.load_full();
+        let headers = HeaderMap::new();
+        let upstream = state
+            .resolve(
+                &config,
+                &UpstreamType::ChatCompletions,
+                None,
+                &headers,
+                None,
+            )
+            .unwrap();
+
+        // The request is still sent to the upstream, even if it can't be built for the shadow
+        let res = send_with_fallback(&state, &upstream, &headers, |target| {
+            if target.name == "candidate" {
+                return Err(AzureError::InternalParsing("invalid model".to_string()));
+            }
+            let uri = append_path_to_uri(target.uri.clone(), "/v1/chat/completions");
+            Ok(Request::post(uri)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Bytes::from(json!({"model": "phi"}).to_string()))
+                .unwrap())
+        })
+        .await
+        .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(state.metrics.encode().contains(
+            r#"azure_openai_proxy_shadow_requests_total{status="error",upstream="candidate"} 1"#
+        ));
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        let config = RetryConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            assert!(backoff(&config, 0) <= Duration::from_millis(100));
+            assert!(backoff(&config, 2) <= Duration::from_millis(400));
+            // Capped to the maximum backoff, even if the exponential backoff overflows
+            assert!(backoff(&config, 64) <= Duration::from_millis(1000));
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::errors::AzureError;
 use axum::http::Uri;
+use std::hash::{BuildHasher, RandomState};
 
 /// Supported Azure AI Model Inference API versions
 ///
@@ -14,7 +15,7 @@ pub fn check_api_version(api_version: Option<String>) -> Result<(), AzureError>
     if !API_VERSIONS.contains(&api_version.as_str()) {
         return Err(AzureError::UnsupportedApiVersionValue(
             api_version.to_string(),
-            API_VERSIONS.join(", ").into(),
+            API_VERSIONS.join(", "),
         ));
     }
 
@@ -32,3 +33,58 @@ pub fn append_path_to_uri(uri: Uri, path: &str) -> Uri {
 
     Uri::from_parts(parts).unwrap()
 }
+
+/// Function to build the URI of the upstream from its host and port, where the host can also be an
+/// Unix domain socket path with the `unix://` scheme e.g. `unix:///run/vllm.sock`
+pub fn build_upstream_uri(host: &str, port: Option<u16>) -> Result<Uri, String> {
+    match host.strip_prefix("unix://") {
+        // The socket path is hex-encoded as the host of the URI, so the upstream port (if any) is
+        // ignored as the connection will go through the Unix domain socket instead
+        #[cfg(unix)]
+        Some(socket_path) => Ok(hyperlocal::Uri::new(socket_path, "/").into()),
+        #[cfg(not(unix))]
+        Some(_) => Err("Unix domain sockets are only supported on Unix platforms".to_string()),
+        None => {
+            let port_str = port.map(|p| format!(":{p}")).unwrap_or_default();
+            Uri::try_from(format!("{}{}", host, port_str)).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Function to generate a fast, non-cryptographic random number, i.e. the hash of a hasher whose
+/// keys are randomly seeded once per thread and then incremented on every call, so it's good
+/// enough for jitter, load balancing and sampling, but not for anything security related
+pub fn random_u64() -> u64 {
+    RandomState::new().hash_one(0u8)
+}
+
+/// Function to hash the bytes with 64-bit FNV-1a, which unlike the `DefaultHasher` has a stable
+/// specification, so the same bytes are hashed the same across requests, restarts and Rust releases
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Serves the router on an ephemeral port of the loopback interface, returning its base URI, so
+/// that the tests can fake an upstream
+#[cfg(test)]
+pub async fn serve_locally(router: axum::Router) -> Uri {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let uri = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    uri.parse().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_hash() {
+        // The reference values of FNV-1a, so that e.g. every replica of the proxy agrees on those
+        assert_eq!(stable_hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(stable_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+}